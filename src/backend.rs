@@ -1,19 +1,56 @@
+use crate::backend::archive::Archive;
+use crate::backend::changes::Changes;
 use crate::backend::console::Console;
 use crate::backend::csv::Csv;
+use crate::backend::exec::Exec;
+use crate::backend::graphite::Graphite;
+use crate::backend::influx::Influx;
+use crate::backend::jsonlines::JsonLines;
+use crate::backend::mqtt::Mqtt;
+use crate::backend::parquet::Parquet;
+use crate::backend::postgres::Postgres;
+use crate::backend::prometheus::Prometheus;
+use crate::backend::sqlite::Sqlite;
+use crate::backend::statsd::Statsd;
+use crate::backend::syslog::Syslog;
+use crate::backend::webhook::Webhook;
+use crate::backend::worker::{Options, Overflow, Worker};
 use crate::device::Device;
 use crate::errors::*;
+use crate::inventory::{Event, Inventory};
 use crate::print_errors;
 use crate::settings;
 
 use error_chain::bail;
 use once_cell::sync::OnceCell;
-use tokio::prelude::*;
 
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+pub mod archive;
+mod backoff;
+mod changes;
 mod console;
 mod csv;
+mod exec;
+mod graphite;
+mod influx;
+mod jsonlines;
+mod mqtt;
+mod parquet;
+mod plaintext;
+mod postgres;
+mod prometheus;
+mod rotation;
+mod sqlite;
+#[cfg(test)]
+mod stand_in;
+mod statsd;
+mod syslog;
+mod template;
+mod webhook;
+mod worker;
 
 static DISPATCHER: OnceCell<Dispatcher> = OnceCell::new();
 
@@ -23,6 +60,16 @@ pub trait Backend<'de>: settings::Named + Sized {
     fn name() -> &'static str;
     fn new(settings: Self::Settings) -> Result<Self>;
     fn log(&mut self, when: Duration, data: &[Device]) -> Result<()>;
+    /// Called with changes to the device inventory. Backends that have no
+    /// use for them just ignore them.
+    fn event(&mut self, _when: Duration, _events: &[Event]) -> Result<()> {
+        Ok(())
+    }
+    /// Whether every call to `log` must see all devices because the
+    /// backend takes devices missing from it as gone.
+    fn needs_snapshots(&self) -> bool {
+        false
+    }
     fn from_settings() -> Result<Self> {
         Self::new(settings::get_for_backend::<Self>()?)
     }
@@ -39,36 +86,65 @@ impl<'de, T: Backend<'de>> settings::Named for T {
     }
 }
 
-struct ToggleBackend<T>(Option<Mutex<T>>);
-
-impl<'de, T> ToggleBackend<T>
-where
-    T: Backend<'de>,
-{
-    fn new(backends: &[String]) -> Result<Self> {
-        let enabled = backends
-            .iter()
-            .any(|x| x.as_str() == <T as settings::Named>::name());
-        let backend = if enabled {
-            Some(Mutex::new(T::from_settings()?))
+struct ToggleBackend {
+    worker: Option<Worker>,
+    // only gets the readings that changed
+    change_only: bool,
+}
+
+impl ToggleBackend {
+    fn new<'de, T>(base: &settings::Base, options: &Options) -> Result<Self>
+    where
+        T: Backend<'de> + Send + 'static,
+    {
+        let name = <T as settings::Named>::name();
+        let enabled = base.backends.iter().any(|x| x.as_str() == name);
+        let change_only = base.change_only.iter().any(|x| x.as_str() == name);
+        let worker = if enabled {
+            let backend = T::from_settings()?;
+            if change_only && backend.needs_snapshots() {
+                bail!(
+                    "Backend \"{}\" needs all devices on every poll. Remove it from change_only",
+                    name
+                );
+            }
+            Some(Worker::spawn(backend, T::from_settings, options.clone())?)
         } else {
             None
         };
-        Ok(Self(backend))
+        Ok(Self {
+            worker,
+            change_only,
+        })
     }
 }
 
 pub struct Dispatcher {
-    console: ToggleBackend<Console>,
-    csv: ToggleBackend<Csv>,
+    console: ToggleBackend,
+    csv: ToggleBackend,
+    exec: ToggleBackend,
+    jsonlines: ToggleBackend,
+    sqlite: ToggleBackend,
+    influx: ToggleBackend,
+    prometheus: ToggleBackend,
+    mqtt: ToggleBackend,
+    graphite: ToggleBackend,
+    statsd: ToggleBackend,
+    webhook: ToggleBackend,
+    postgres: ToggleBackend,
+    syslog: ToggleBackend,
+    archive: ToggleBackend,
+    parquet: ToggleBackend,
+    changes: Option<Mutex<Changes>>,
+    inventory: Option<Mutex<Inventory>>,
 }
 
 impl Dispatcher {
-    fn new(enabled_backends: &[String]) -> Result<Self> {
+    fn new(base: &settings::Base) -> Result<Self> {
         let backends = Self::register_backends()?;
         settings::refresh()?;
 
-        for backend in enabled_backends {
+        for backend in base.backends.iter().chain(&base.change_only) {
             if !backends.iter().any(|x| x == backend) {
                 bail!(
                     "Backend \"{}\" does not exist. These we do know: {:?}",
@@ -78,38 +154,81 @@ impl Dispatcher {
             }
         }
 
+        if base.queue_size < 1 {
+            bail!("queue_size must be at least 1");
+        }
+        let options = Options {
+            queue_size: base.queue_size,
+            block_timeout: Duration::from_secs(base.block_timeout),
+            write_timeout: Duration::from_secs(base.write_timeout),
+            overflow: Overflow::parse(&base.overflow)?,
+            spool_dir: PathBuf::from(&base.spool_dir),
+        };
+
         let ret = Self {
-            console: ToggleBackend::new(enabled_backends)?,
-            csv: ToggleBackend::new(enabled_backends)?,
+            console: ToggleBackend::new::<Console>(base, &options)?,
+            csv: ToggleBackend::new::<Csv>(base, &options)?,
+            exec: ToggleBackend::new::<Exec>(base, &options)?,
+            jsonlines: ToggleBackend::new::<JsonLines>(base, &options)?,
+            sqlite: ToggleBackend::new::<Sqlite>(base, &options)?,
+            influx: ToggleBackend::new::<Influx>(base, &options)?,
+            prometheus: ToggleBackend::new::<Prometheus>(base, &options)?,
+            mqtt: ToggleBackend::new::<Mqtt>(base, &options)?,
+            graphite: ToggleBackend::new::<Graphite>(base, &options)?,
+            statsd: ToggleBackend::new::<Statsd>(base, &options)?,
+            webhook: ToggleBackend::new::<Webhook>(base, &options)?,
+            postgres: ToggleBackend::new::<Postgres>(base, &options)?,
+            syslog: ToggleBackend::new::<Syslog>(base, &options)?,
+            archive: ToggleBackend::new::<Archive>(base, &options)?,
+            parquet: ToggleBackend::new::<Parquet>(base, &options)?,
+            changes: if base.change_only.is_empty() {
+                None
+            } else {
+                Some(Mutex::new(Changes::new(&base.deadbands, base.heartbeat)?))
+            },
+            inventory: if base.inventory_file.is_empty() {
+                None
+            } else {
+                Some(Mutex::new(Inventory::load(Path::new(
+                    &base.inventory_file,
+                ))?))
+            },
         };
         Ok(ret)
     }
 
-    fn call_backend<'de, B: Backend<'de> + Send>(
+    fn call_backend(
         t: Duration,
-        devices: Arc<Vec<Device>>,
-        backend: &'static ToggleBackend<B>,
+        devices: &Arc<Vec<Device>>,
+        changed: Option<&Arc<Vec<Device>>>,
+        backend: &ToggleBackend,
     ) {
         // Backend disabled?
-        let backend = match backend {
-            ToggleBackend(Some(back)) => back,
-            _ => return,
-        };
+        if let Some(worker) = &backend.worker {
+            match changed {
+                Some(changed) if backend.change_only => {
+                    // no need to wake up the worker for nothing
+                    if !changed.is_empty() {
+                        worker.push(t, changed);
+                    }
+                }
+                _ => worker.push(t, devices),
+            }
+        }
+    }
 
-        tokio::spawn(future::lazy(move || {
-            backend.lock().unwrap().log(t, &devices).map_err(|e| {
-                let err = Error::with_chain(e, format!("Backend {} failed", Console::name()));
-                print_errors(&err);
-            })
-        }));
+    fn call_events(t: Duration, events: &Arc<Vec<Event>>, backend: &ToggleBackend) {
+        if let Some(worker) = &backend.worker {
+            worker.push_events(t, events);
+        }
     }
 
     fn get() -> &'static Self {
         DISPATCHER.get().expect("Dispatcher not initialized.")
     }
 
-    pub fn init(backends: &[String]) -> Result<()> {
-        let dispatcher = Self::new(backends)?;
+    pub fn init(base: &settings::Base) -> Result<()> {
+        let dispatcher = Self::new(base)?;
         DISPATCHER
             .set(dispatcher)
             .map_err(|_| "Dispatcher can only be initialized once".into())
@@ -117,15 +236,76 @@ impl Dispatcher {
 
     pub fn dispatch(time: Duration, devices: &Arc<Vec<Device>>) {
         let dispatcher = Self::get();
-        Self::call_backend(time, devices.clone(), &dispatcher.console);
-        Self::call_backend(time, devices.clone(), &dispatcher.csv);
+        if let Some(inventory) = &dispatcher.inventory {
+            let mut inventory = inventory.lock().unwrap();
+            let events = inventory.update(time, devices);
+            if !events.is_empty() {
+                if let Err(e) = inventory.save() {
+                    print_errors(Error::with_chain(e, "Cannot save device inventory"));
+                }
+                // events go first so that readings can be attributed to the new name
+                Self::dispatch_events(time, events);
+            }
+        }
+        let changed = dispatcher
+            .changes
+            .as_ref()
+            .map(|changes| Arc::new(changes.lock().unwrap().filter(time, devices)));
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.console);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.csv);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.exec);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.jsonlines);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.sqlite);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.influx);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.prometheus);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.mqtt);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.graphite);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.statsd);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.webhook);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.postgres);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.syslog);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.archive);
+        Self::call_backend(time, devices, changed.as_ref(), &dispatcher.parquet);
+    }
+
+    fn dispatch_events(time: Duration, events: Vec<Event>) {
+        let dispatcher = Self::get();
+        let events = Arc::new(events);
+        Self::call_events(time, &events, &dispatcher.console);
+        Self::call_events(time, &events, &dispatcher.csv);
+        Self::call_events(time, &events, &dispatcher.exec);
+        Self::call_events(time, &events, &dispatcher.jsonlines);
+        Self::call_events(time, &events, &dispatcher.sqlite);
+        Self::call_events(time, &events, &dispatcher.influx);
+        Self::call_events(time, &events, &dispatcher.prometheus);
+        Self::call_events(time, &events, &dispatcher.mqtt);
+        Self::call_events(time, &events, &dispatcher.graphite);
+        Self::call_events(time, &events, &dispatcher.statsd);
+        Self::call_events(time, &events, &dispatcher.webhook);
+        Self::call_events(time, &events, &dispatcher.postgres);
+        Self::call_events(time, &events, &dispatcher.syslog);
+        Self::call_events(time, &events, &dispatcher.archive);
+        Self::call_events(time, &events, &dispatcher.parquet);
     }
 
     pub fn register_backends() -> Result<Vec<String>> {
-        let mut backends = Vec::with_capacity(2);
+        let mut backends = Vec::with_capacity(15);
 
         Console::register(&mut backends)?;
         Csv::register(&mut backends)?;
+        Exec::register(&mut backends)?;
+        JsonLines::register(&mut backends)?;
+        Sqlite::register(&mut backends)?;
+        Influx::register(&mut backends)?;
+        Prometheus::register(&mut backends)?;
+        Mqtt::register(&mut backends)?;
+        Graphite::register(&mut backends)?;
+        Statsd::register(&mut backends)?;
+        Webhook::register(&mut backends)?;
+        Postgres::register(&mut backends)?;
+        Syslog::register(&mut backends)?;
+        Archive::register(&mut backends)?;
+        Parquet::register(&mut backends)?;
 
         Ok(backends)
     }
@@ -0,0 +1,533 @@
+use super::plaintext::sanitize;
+use super::Backend;
+use crate::device::{Device, Recorded};
+use crate::errors::*;
+use crate::settings;
+
+use chrono::DateTime;
+use config::Value;
+use error_chain::bail;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"FRITZRR1";
+// magic and number of archives
+const HEADER_LEN: u64 = 12;
+// consolidation, step, rows, samples in the current row and its start
+const ARCHIVE_HEADER_LEN: u64 = 24;
+const CONSOLIDATIONS: &[&str] = &["Average", "Min", "Max", "Last"];
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    out_dir: String,
+    archives: Vec<String>,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Archive> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("out_dir".into(), "archive".into()),
+            (
+                "archives".into(),
+                vec![
+                    "Average:60:2880",
+                    "Average:900:8640",
+                    "Min:900:8640",
+                    "Max:900:8640",
+                    "Average:3600:43800",
+                ]
+                .into(),
+            ),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+pub struct Archive {
+    settings: Settings,
+    definitions: Vec<Definition>,
+    files: HashMap<String, RoundRobin>,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Archive {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Archive"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        if settings.archives.is_empty() {
+            bail!("At least one archive must be configured");
+        }
+        let definitions = settings
+            .archives
+            .iter()
+            .map(|a| Definition::parse(a))
+            .collect::<Result<Vec<_>>>()?;
+        fs::create_dir_all(&settings.out_dir)
+            .chain_err(|| format!("Cannot create directory {}", settings.out_dir))?;
+
+        let ret = Self {
+            settings,
+            definitions,
+            files: HashMap::new(),
+            recorded,
+        };
+        Ok(ret)
+    }
+
+    /// Suppressed devices would be consolidated as gaps.
+    fn needs_snapshots(&self) -> bool {
+        true
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        for device in data {
+            for (measurement, value) in device.readings(self.recorded) {
+                let name = format!("{}-{}.rra", sanitize(&device.common.unique_id), measurement);
+                self.file(&name)?
+                    .update(when.as_secs(), value)
+                    .chain_err(|| format!("Cannot update archive {}", name))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Archive {
+    fn file(&mut self, name: &str) -> Result<&mut RoundRobin> {
+        if !self.files.contains_key(name) {
+            let path = Path::new(&self.settings.out_dir).join(name);
+            let file = if path.exists() {
+                RoundRobin::open(&path)?
+            } else {
+                RoundRobin::create(&path, &self.definitions)
+                    .chain_err(|| format!("Cannot create archive {}", path.display()))?
+            };
+            if file.definitions() != self.definitions {
+                bail!(
+                    "Archive {} was created with different archives. Move it away to start over",
+                    path.display()
+                );
+            }
+            self.files.insert(name.to_owned(), file);
+        }
+        Ok(self.files.get_mut(name).expect("Archive was just opened."))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Definition {
+    consolidation: u32,
+    step: u32,
+    rows: u32,
+}
+
+impl Definition {
+    /// Parses an archive like "Average:900:8640", i.e. the consolidation
+    /// function, the seconds per row and the number of rows.
+    fn parse(archive: &str) -> Result<Self> {
+        let parts: Vec<_> = archive.split(':').collect();
+        if parts.len() != 3 {
+            bail!(
+                "Archive \"{}\" must look like \"Consolidation:step:rows\"",
+                archive
+            );
+        }
+        let consolidation = CONSOLIDATIONS
+            .iter()
+            .position(|c| *c == parts[0])
+            .chain_err(|| {
+                format!(
+                    "Consolidation \"{}\" does not exist. These we do know: {:?}",
+                    parts[0], CONSOLIDATIONS
+                )
+            })?;
+        let step = parts[1]
+            .parse()
+            .chain_err(|| format!("Invalid step in archive \"{}\"", archive))?;
+        let rows = parts[2]
+            .parse()
+            .chain_err(|| format!("Invalid number of rows in archive \"{}\"", archive))?;
+        if step == 0 || rows == 0 {
+            bail!("Archive \"{}\" must have a positive step and rows", archive);
+        }
+
+        Ok(Self {
+            consolidation: u32::try_from(consolidation).expect("There are only 4 consolidations."),
+            step,
+            rows,
+        })
+    }
+
+    fn name(self) -> &'static str {
+        CONSOLIDATIONS
+            .get(self.consolidation as usize)
+            .unwrap_or(&"Unknown")
+    }
+}
+
+struct Level {
+    definition: Definition,
+    // samples consolidated into the row starting at `current`
+    count: u32,
+    current: u64,
+    offset: u64,
+}
+
+/// A file of fixed size holding a ring buffer of rows per archive.
+/// Unknown rows are stored as NaN.
+struct RoundRobin {
+    file: File,
+    levels: Vec<Level>,
+}
+
+impl RoundRobin {
+    fn create(path: &Path, definitions: &[Definition]) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .chain_err(|| format!("Cannot open archive {}", path.display()))?;
+
+        let mut writer = BufWriter::new(&file);
+        writer
+            .write_all(MAGIC)
+            .chain_err(|| "Cannot write header")?;
+        writer
+            .write_all(&to_u32(definitions.len()).to_le_bytes())
+            .chain_err(|| "Cannot write header")?;
+        for definition in definitions {
+            write_definition(&mut writer, *definition, 0, 0).chain_err(|| "Cannot write header")?;
+        }
+        let unknown = f64::NAN.to_bits().to_le_bytes();
+        for definition in definitions {
+            for _ in 0..definition.rows {
+                writer
+                    .write_all(&unknown)
+                    .chain_err(|| "Cannot preallocate rows")?;
+            }
+        }
+        writer.flush().chain_err(|| "Cannot preallocate rows")?;
+        drop(writer);
+
+        Self::from_file(file).chain_err(|| format!("Cannot read archive {}", path.display()))
+    }
+
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .chain_err(|| format!("Cannot open archive {}", path.display()))?;
+        Self::from_file(file).chain_err(|| format!("Cannot read archive {}", path.display()))
+    }
+
+    fn from_file(mut file: File) -> Result<Self> {
+        let mut magic = [0; 8];
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.read_exact(&mut magic))
+            .chain_err(|| "Cannot read header")?;
+        if &magic != MAGIC {
+            bail!("Not an archive");
+        }
+        let count = read_u32(&mut file).chain_err(|| "Cannot read header")?;
+
+        let mut levels = Vec::new();
+        let mut offset = HEADER_LEN + u64::from(count) * ARCHIVE_HEADER_LEN;
+        for _ in 0..count {
+            let definition = Definition {
+                consolidation: read_u32(&mut file).chain_err(|| "Cannot read header")?,
+                step: read_u32(&mut file).chain_err(|| "Cannot read header")?,
+                rows: read_u32(&mut file).chain_err(|| "Cannot read header")?,
+            };
+            // the same as `Definition::parse` checks as dividing by zero panics
+            if definition.consolidation as usize >= CONSOLIDATIONS.len() {
+                bail!("Unknown consolidation {}", definition.consolidation);
+            }
+            if definition.step == 0 || definition.rows == 0 {
+                bail!("Archive must have a positive step and rows");
+            }
+            let count = read_u32(&mut file).chain_err(|| "Cannot read header")?;
+            let current = read_u64(&mut file).chain_err(|| "Cannot read header")?;
+            levels.push(Level {
+                definition,
+                count,
+                current,
+                offset,
+            });
+            offset += u64::from(definition.rows) * 8;
+        }
+
+        let len = file.metadata().chain_err(|| "Cannot stat archive")?.len();
+        if len != offset {
+            bail!("Archive is truncated");
+        }
+        Ok(Self { file, levels })
+    }
+
+    fn definitions(&self) -> Vec<Definition> {
+        self.levels.iter().map(|l| l.definition).collect()
+    }
+
+    fn update(&mut self, timestamp: u64, value: f64) -> Result<()> {
+        for i in 0..self.levels.len() {
+            let Level {
+                definition,
+                count,
+                current,
+                ..
+            } = self.levels[i];
+            let step = u64::from(definition.step);
+            let start = timestamp - timestamp % step;
+            if start < current {
+                // too old, the row is gone already
+                continue;
+            }
+
+            let (count, value) = if start == current {
+                let old = self.read_row(i, start)?;
+                let count = count.saturating_add(1);
+                (count, consolidate(definition, old, value, count))
+            } else {
+                // mark skipped rows as unknown, at most one full round
+                let missing = if current == 0 {
+                    0
+                } else {
+                    ((start - current) / step - 1).min(u64::from(definition.rows))
+                };
+                for n in 1..=missing {
+                    self.write_row(i, start - n * step, f64::NAN)?;
+                }
+                (1, value)
+            };
+
+            self.write_row(i, start, value)?;
+            self.levels[i].count = count;
+            self.levels[i].current = start;
+            let position = HEADER_LEN + to_u64(i) * ARCHIVE_HEADER_LEN;
+            let file = &mut self.file;
+            file.seek(SeekFrom::Start(position))
+                .and_then(|_| write_definition(file, definition, count, start))
+                .chain_err(|| "Cannot write header")?;
+        }
+        Ok(())
+    }
+
+    fn position(&self, level: usize, timestamp: u64) -> u64 {
+        let definition = self.levels[level].definition;
+        let row = timestamp / u64::from(definition.step) % u64::from(definition.rows);
+        self.levels[level].offset + row * 8
+    }
+
+    fn read_row(&mut self, level: usize, timestamp: u64) -> Result<f64> {
+        let position = self.position(level, timestamp);
+        self.file
+            .seek(SeekFrom::Start(position))
+            .and_then(|_| read_u64(&mut self.file))
+            .map(f64::from_bits)
+            .chain_err(|| "Cannot read row")
+    }
+
+    fn write_row(&mut self, level: usize, timestamp: u64, value: f64) -> Result<()> {
+        let position = self.position(level, timestamp);
+        self.file
+            .seek(SeekFrom::Start(position))
+            .and_then(|_| self.file.write_all(&value.to_bits().to_le_bytes()))
+            .chain_err(|| "Cannot write row")
+    }
+}
+
+fn consolidate(definition: Definition, old: f64, value: f64, count: u32) -> f64 {
+    if old.is_nan() {
+        return value;
+    }
+    match definition.name() {
+        "Average" => old + (value - old) / f64::from(count),
+        "Min" => old.min(value),
+        "Max" => old.max(value),
+        _ => value,
+    }
+}
+
+/// Writes all rows of an archive file as CSV, oldest first.
+pub fn dump(path: &str) -> Result<()> {
+    let mut archive = RoundRobin::open(Path::new(path))?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "consolidation,step,timestamp,value").chain_err(|| "Cannot write to stdout")?;
+
+    for i in 0..archive.levels.len() {
+        let definition = archive.levels[i].definition;
+        let current = archive.levels[i].current;
+        let step = u64::from(definition.step);
+        let rows = u64::from(definition.rows);
+        if current == 0 {
+            continue;
+        }
+        let mut timestamp = current.saturating_sub((rows - 1) * step);
+        while timestamp <= current {
+            let value = archive.read_row(i, timestamp)?;
+            if !value.is_nan() {
+                let secs = i64::try_from(timestamp).chain_err(|| "Timestamp out of range")?;
+                let time =
+                    DateTime::from_timestamp(secs, 0).chain_err(|| "Timestamp out of range")?;
+                writeln!(
+                    out,
+                    "{},{},{},{}",
+                    definition.name(),
+                    step,
+                    time.format("%Y-%m-%dT%H:%M:%SZ"),
+                    value
+                )
+                .chain_err(|| "Cannot write to stdout")?;
+            }
+            timestamp += step;
+        }
+    }
+    Ok(())
+}
+
+fn write_definition<W: Write>(
+    writer: &mut W,
+    definition: Definition,
+    count: u32,
+    current: u64,
+) -> io::Result<()> {
+    writer.write_all(&definition.consolidation.to_le_bytes())?;
+    writer.write_all(&definition.step.to_le_bytes())?;
+    writer.write_all(&definition.rows.to_le_bytes())?;
+    writer.write_all(&count.to_le_bytes())?;
+    writer.write_all(&current.to_le_bytes())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn to_u32(n: usize) -> u32 {
+    u32::try_from(n).expect("There are not that many archives.")
+}
+
+fn to_u64(n: usize) -> u64 {
+    u64::try_from(n).expect("usize fits into u64.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+    use std::process;
+
+    fn temp_path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("fritzlogger-{}-{}.rra", test, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn definitions(archives: &[&str]) -> Vec<Definition> {
+        archives
+            .iter()
+            .map(|a| Definition::parse(a).unwrap())
+            .collect()
+    }
+
+    fn rows(archive: &mut RoundRobin, level: usize, timestamps: &[u64]) -> Vec<f64> {
+        timestamps
+            .iter()
+            .map(|t| archive.read_row(level, *t).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn consolidates_rows() {
+        let path = temp_path("consolidate");
+        let definitions = definitions(&[
+            "Average:60:10",
+            "Min:60:10",
+            "Max:60:10",
+            "Last:60:10",
+            "Average:300:4",
+        ]);
+        let mut archive = RoundRobin::create(&path, &definitions).unwrap();
+        for (timestamp, value) in &[(600, 1.0), (610, 3.0), (620, 2.0), (720, 5.0)] {
+            archive.update(*timestamp, *value).unwrap();
+        }
+        // too old for the minute archives but not for the 5 minute one
+        archive.update(690, 0.0).unwrap();
+
+        assert_eq!(rows(&mut archive, 0, &[600, 720]), [2.0, 5.0]);
+        assert_eq!(rows(&mut archive, 1, &[600, 720]), [1.0, 5.0]);
+        assert_eq!(rows(&mut archive, 2, &[600, 720]), [3.0, 5.0]);
+        assert_eq!(rows(&mut archive, 3, &[600, 720]), [2.0, 5.0]);
+        assert_eq!(rows(&mut archive, 4, &[600]), [2.2]);
+        // skipped rows are unknown
+        assert!(archive.read_row(0, 660).unwrap().is_nan());
+
+        let mut archive = RoundRobin::open(&path).unwrap();
+        assert!(archive.definitions() == definitions);
+        assert_eq!(archive.levels[0].current, 720);
+        assert_eq!(archive.levels[4].count, 5);
+        assert_eq!(rows(&mut archive, 0, &[600]), [2.0]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn forgets_rows_after_a_round() {
+        let path = temp_path("round");
+        let mut archive = RoundRobin::create(&path, &definitions(&["Last:60:3"])).unwrap();
+        archive.update(60, 1.0).unwrap();
+        archive.update(600, 2.0).unwrap();
+        // rows in between are unknown, the one of 60 is overwritten
+        assert!(archive.read_row(0, 480).unwrap().is_nan());
+        assert!(archive.read_row(0, 540).unwrap().is_nan());
+        assert_eq!(rows(&mut archive, 0, &[60]), [2.0]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_zero_step_and_rows() {
+        let path = temp_path("zero");
+        let mut content = MAGIC.to_vec();
+        content.extend_from_slice(&1_u32.to_le_bytes());
+        write_definition(
+            &mut content,
+            Definition {
+                consolidation: 0,
+                step: 0,
+                rows: 1,
+            },
+            0,
+            0,
+        )
+        .unwrap();
+        content.extend_from_slice(&[0; 8]);
+        fs::write(&path, content).unwrap();
+        assert!(RoundRobin::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+
+        assert!(Definition::parse("Average:0:10").is_err());
+        assert!(Definition::parse("Average:60:0").is_err());
+        assert!(Definition::parse("Median:60:10").is_err());
+    }
+}
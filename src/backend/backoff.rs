@@ -0,0 +1,42 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/// Exponential backoff for backends that need to (re)establish something
+/// like a child process or a connection.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    pub fn new(min_secs: u64, max_secs: u64) -> Self {
+        let min = Duration::from_secs(min_secs);
+        Self {
+            min,
+            max: cmp::max(min, Duration::from_secs(max_secs)),
+            current: min,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// Time left until the next attempt is allowed. `None` if we may try now.
+    pub fn remaining(&self) -> Option<Duration> {
+        let now = Instant::now();
+        if now < self.next_attempt {
+            Some(self.next_attempt - now)
+        } else {
+            None
+        }
+    }
+
+    pub fn failed(&mut self) {
+        self.next_attempt = Instant::now() + self.current;
+        self.current = cmp::min(self.current * 2, self.max);
+    }
+
+    pub fn succeeded(&mut self) {
+        self.current = self.min;
+    }
+}
@@ -0,0 +1,231 @@
+use crate::device::{Device, READINGS};
+use crate::errors::*;
+
+use error_chain::bail;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+const TEMPERATURE: &[&str] = &["temperature", "sensor_temperature", "offset"];
+const POWERMETER: &[&str] = &["power", "voltage", "energy"];
+
+struct Emitted {
+    value: f64,
+    secs: u64,
+}
+
+/// Drops readings that did not change since they were last passed on.
+///
+/// Every measurement of a device is compared on its own. It is passed on
+/// when it moved further than its deadband away from the value last passed
+/// on or when its heartbeat is due. The others are listed as unchanged in
+/// the device. Devices without any passed on measurement are dropped
+/// altogether.
+pub struct Changes {
+    deadbands: Vec<(String, f64)>,
+    heartbeat: u64,
+    // last passed on value by AIN and measurement
+    last: HashMap<(String, &'static str), Emitted>,
+}
+
+impl Changes {
+    /// Deadbands are given as `measurement:threshold` in SI units, e.g.
+    /// `temperature:0.2`. Measurements without one pass on every change.
+    pub fn new(deadbands: &[String], heartbeat: u64) -> Result<Self> {
+        let deadbands = deadbands
+            .iter()
+            .map(|d| parse_deadband(d))
+            .collect::<Result<Vec<_>>>()?;
+        let ret = Self {
+            deadbands,
+            heartbeat,
+            last: HashMap::new(),
+        };
+        Ok(ret)
+    }
+
+    pub fn filter(&mut self, when: Duration, devices: &[Device]) -> Vec<Device> {
+        let secs = when.as_secs();
+        let mut changed = Vec::new();
+
+        for device in devices {
+            let ain = &device.common.unique_id;
+            let values = values(device);
+            let count = values.len();
+            let unchanged: Vec<String> = values
+                .into_iter()
+                .filter(|(measurement, value)| !self.changed(ain, measurement, *value, secs))
+                .map(|(measurement, _)| measurement.to_owned())
+                .collect();
+            if unchanged.len() == count {
+                continue;
+            }
+
+            let passes = |group: &[&str]| group.iter().any(|m| !unchanged.iter().any(|u| u == m));
+            changed.push(Device {
+                common: device.common.clone(),
+                temperature: device.temperature.clone().filter(|_| passes(TEMPERATURE)),
+                powermeter: device.powermeter.clone().filter(|_| passes(POWERMETER)),
+                unchanged,
+            });
+        }
+        changed
+    }
+
+    fn changed(&mut self, ain: &str, measurement: &'static str, value: f64, secs: u64) -> bool {
+        let key = (ain.to_owned(), measurement);
+        if let Some(last) = self.last.get(&key) {
+            let heartbeat = self.heartbeat > 0 && secs.saturating_sub(last.secs) >= self.heartbeat;
+            let moved = (value - last.value).abs() > self.deadband(measurement);
+            if !heartbeat && !moved {
+                return false;
+            }
+        }
+        self.last.insert(key, Emitted { value, secs });
+        true
+    }
+
+    /// The sensor follows the reported temperature. Hence it falls back to
+    /// its deadband as it would pass on every change otherwise.
+    fn deadband(&self, measurement: &str) -> f64 {
+        let find = |measurement| {
+            self.deadbands
+                .iter()
+                .find(|(name, _)| name == measurement)
+                .map(|(_, threshold)| *threshold)
+        };
+        let threshold = match measurement {
+            "sensor_temperature" => find(measurement).or_else(|| find("temperature")),
+            _ => find(measurement),
+        };
+        threshold.unwrap_or(0.0)
+    }
+}
+
+/// All measurements of the device in SI units, whichever are recorded.
+fn values(device: &Device) -> Vec<(&'static str, f64)> {
+    let mut values = vec![("present", f64::from(u8::from(device.common.present)))];
+    if let Some(t) = &device.temperature {
+        values.push(("temperature", t.temperature.value()));
+        values.push(("sensor_temperature", t.sensor.value()));
+        values.push(("offset", t.offset.value()));
+    }
+    if let Some(p) = &device.powermeter {
+        values.push(("power", p.power.value()));
+        values.push(("voltage", p.voltage.value()));
+        values.push(("energy", p.energy.value()));
+    }
+    values
+}
+
+fn parse_deadband(deadband: &str) -> Result<(String, f64)> {
+    let mut parts = deadband.splitn(2, ':');
+    let measurement = parts.next().unwrap_or_default().trim();
+    let threshold = match parts.next().map(|t| t.trim().parse::<f64>()) {
+        Some(Ok(threshold)) if threshold >= 0.0 => threshold,
+        _ => bail!(
+            "Deadband \"{}\" is invalid. Use measurement:threshold, e.g. temperature:0.2",
+            deadband
+        ),
+    };
+    if !READINGS.contains(&measurement) {
+        bail!(
+            "Measurement \"{}\" does not exist. Use one of {:?}",
+            measurement,
+            READINGS
+        );
+    }
+    Ok((measurement.to_owned(), threshold))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::tests::device;
+    use crate::device::{Celsius, Recorded, Volts, WattHours, Watts};
+
+    fn filter(changes: &mut Changes, secs: u64, devices: &[Device]) -> Vec<&'static str> {
+        let passed = changes.filter(Duration::from_secs(secs), devices);
+        assert!(passed.len() <= 1);
+        passed
+            .iter()
+            .flat_map(|device| device.readings(Recorded::Both))
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    fn deadbands() -> Vec<String> {
+        vec![
+            "temperature:0.2".to_owned(),
+            "power:1".to_owned(),
+            "voltage:2".to_owned(),
+        ]
+    }
+
+    #[test]
+    fn passes_on_first_poll() {
+        let mut changes = Changes::new(&deadbands(), 0).unwrap();
+        assert_eq!(
+            filter(&mut changes, 0, &[device("1")]).len(),
+            READINGS.len()
+        );
+    }
+
+    #[test]
+    fn drops_changes_within_deadband() {
+        let mut changes = Changes::new(&deadbands(), 0).unwrap();
+        let mut desk = device("1");
+        filter(&mut changes, 0, &[desk.clone()]);
+
+        desk.temperature.as_mut().unwrap().temperature = Celsius(217);
+        desk.temperature.as_mut().unwrap().sensor = Celsius(222);
+        desk.powermeter.as_mut().unwrap().power = Watts(13_000);
+        assert!(filter(&mut changes, 60, &[desk.clone()]).is_empty());
+
+        // compared to the value passed on last, not the previous poll
+        desk.powermeter.as_mut().unwrap().power = Watts(13_600);
+        assert_eq!(filter(&mut changes, 120, &[desk]), ["power"]);
+    }
+
+    #[test]
+    fn passes_measurements_on_their_own() {
+        let mut changes = Changes::new(&deadbands(), 0).unwrap();
+        let mut desk = device("1");
+        filter(&mut changes, 0, &[desk.clone()]);
+
+        for (secs, volts) in [(60, 230_700), (120, 229_400), (180, 231_100)] {
+            desk.powermeter.as_mut().unwrap().voltage = Volts(volts);
+            assert!(filter(&mut changes, secs, &[desk.clone()]).is_empty());
+        }
+
+        desk.powermeter.as_mut().unwrap().voltage = Volts(227_500);
+        desk.powermeter.as_mut().unwrap().energy = WattHours(4712);
+        assert_eq!(filter(&mut changes, 240, &[desk]), ["voltage", "energy"]);
+    }
+
+    #[test]
+    fn passes_on_every_change_without_deadband() {
+        let mut changes = Changes::new(&[], 0).unwrap();
+        let mut desk = device("1");
+        filter(&mut changes, 0, &[desk.clone()]);
+        desk.temperature.as_mut().unwrap().offset = Celsius(-4);
+        assert_eq!(filter(&mut changes, 60, &[desk]), ["offset"]);
+    }
+
+    #[test]
+    fn repeats_on_heartbeat() {
+        let mut changes = Changes::new(&deadbands(), 900).unwrap();
+        let desk = [device("1")];
+        filter(&mut changes, 0, &desk);
+        assert!(filter(&mut changes, 899, &desk).is_empty());
+        assert_eq!(filter(&mut changes, 900, &desk).len(), READINGS.len());
+        assert!(filter(&mut changes, 960, &desk).is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_deadbands() {
+        assert!(Changes::new(&["temperature".to_owned()], 0).is_err());
+        assert!(Changes::new(&["temperature:-1".to_owned()], 0).is_err());
+        assert!(Changes::new(&["humidity:1".to_owned()], 0).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use super::Backend;
 use crate::device::Device;
 use crate::errors::*;
+use crate::inventory::Event;
 use crate::settings;
 
 use std::time::Duration;
@@ -23,4 +24,9 @@ impl<'de> Backend<'de> for Console {
         println!("{:#?}", data);
         Ok(())
     }
+
+    fn event(&mut self, _: Duration, events: &[Event]) -> Result<()> {
+        println!("{:#?}", events);
+        Ok(())
+    }
 }
@@ -1,45 +1,109 @@
 use super::Backend;
-use crate::device::Device;
+use crate::device::{Device, Recorded};
 use crate::errors::*;
+use crate::inventory::Event;
 use crate::settings;
 
 use config::Value;
-use csv::{Writer, WriterBuilder};
+use csv::{QuoteStyle, WriterBuilder};
+use error_chain::bail;
+use format::{Column, Format, Zone};
 use serde::{Deserialize, Serialize};
+use table::{long_tables, DeviceTables, EventTable, LongTable, WideTable};
 
-use std::fs::{File, OpenOptions};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+mod format;
+mod outfile;
+mod table;
+
 #[derive(Deserialize, Serialize)]
 pub struct Settings {
     out_dir: String,
+    rotate: String,
+    max_size: u64,
+    compress: bool,
+    retention: u64,
+    temperature_columns: Vec<String>,
+    energy_columns: Vec<String>,
+    delimiter: String,
+    quoting: String,
+    decimal_separator: String,
+    timezone: String,
+    header_mismatch: String,
+    layout: String,
+    close_after: u64,
+    temperature: String,
 }
 
 impl<'de> settings::Settings<'de, Csv> for Settings {
     fn defaults() -> Vec<(String, Value)> {
-        vec![("out_dir".into(), ".".into())]
+        vec![
+            ("out_dir".into(), ".".into()),
+            ("rotate".into(), "None".into()),
+            ("max_size".into(), 10_000_000.into()),
+            ("compress".into(), false.into()),
+            ("retention".into(), 0.into()),
+            (
+                "temperature_columns".into(),
+                vec!["timestamp", "id", "temperature", "offset"].into(),
+            ),
+            (
+                "energy_columns".into(),
+                vec!["timestamp", "id", "voltage", "power"].into(),
+            ),
+            ("delimiter".into(), ",".into()),
+            ("quoting".into(), "Necessary".into()),
+            ("decimal_separator".into(), ".".into()),
+            ("timezone".into(), "Utc".into()),
+            ("header_mismatch".into(), "Rotate".into()),
+            ("layout".into(), "Long".into()),
+            ("close_after".into(), 3600.into()),
+            ("temperature".into(), "Both".into()),
+        ]
     }
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum Rotate {
+    None,
+    Daily,
+    Monthly,
+    Size,
+}
+
+/// What to do when an existing file has a different header than configured.
+#[derive(Clone, Copy)]
+enum Mismatch {
+    /// Move the old file aside and start a new one.
+    Rotate,
+    /// Rewrite the old file with the new columns, matched by name.
+    Rewrite,
+    Fail,
+}
+
 pub struct Csv {
-    writer_temperature: Writer<File>,
-    writer_energy: Writer<File>,
+    options: Options,
+    layout: Layout,
+    events: EventTable,
 }
 
-#[derive(Serialize)]
-struct RecordTemperature<'a> {
-    timestamp: u64,
-    id: &'a str,
-    temperature: i16,
-    offset: i16,
+enum Layout {
+    Long(Vec<LongTable>),
+    Wide(Vec<WideTable>),
+    PerDevice(DeviceTables),
 }
 
-#[derive(Serialize)]
-struct RecordEnergy<'a> {
-    timestamp: u64,
-    id: &'a str,
-    voltage: u32,
-    power: u32,
+// everything an outfile needs to know to write and rotate itself
+struct Options {
+    settings: Settings,
+    rotate: Rotate,
+    format: Format,
+    delimiter: u8,
+    builder: WriterBuilder,
+    mismatch: Mismatch,
 }
 
 impl<'de> Backend<'de> for Csv {
@@ -50,105 +114,148 @@ impl<'de> Backend<'de> for Csv {
     }
 
     fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let rotate = match settings.rotate.as_str() {
+            "None" => Rotate::None,
+            "Daily" => Rotate::Daily,
+            "Monthly" => Rotate::Monthly,
+            "Size" => Rotate::Size,
+            _ => bail!(
+                "Rotate \"{}\" does not exist. Use None, Daily, Monthly or Size",
+                settings.rotate
+            ),
+        };
+        let quoting = match settings.quoting.as_str() {
+            "Necessary" => QuoteStyle::Necessary,
+            "Always" => QuoteStyle::Always,
+            "NonNumeric" => QuoteStyle::NonNumeric,
+            "Never" => QuoteStyle::Never,
+            _ => bail!(
+                "Quoting \"{}\" does not exist. Use Necessary, Always, NonNumeric or Never",
+                settings.quoting
+            ),
+        };
+        let delimiter = match settings.delimiter.as_bytes() {
+            [delimiter] => *delimiter,
+            _ => bail!("Delimiter must be a single ASCII character"),
+        };
+        let mismatch = match settings.header_mismatch.as_str() {
+            "Rotate" => Mismatch::Rotate,
+            "Rewrite" => Mismatch::Rewrite,
+            "Fail" => Mismatch::Fail,
+            _ => bail!(
+                "Header mismatch \"{}\" does not exist. Use Rotate, Rewrite or Fail",
+                settings.header_mismatch
+            ),
+        };
+        let mut builder = WriterBuilder::new();
+        builder.delimiter(delimiter).quote_style(quoting);
+
+        let format = Format {
+            zone: Zone::parse(&settings.timezone)?,
+            decimal_separator: settings.decimal_separator.clone(),
+            recorded,
+        };
+        let dir = PathBuf::from(&settings.out_dir);
+        let temperature_columns = parse_columns("temperature", &settings.temperature_columns)?;
+        let energy_columns = parse_columns("energy", &settings.energy_columns)?;
+        let events = EventTable::new(dir.clone());
+        let layout = match settings.layout.as_str() {
+            "Long" => Layout::Long(long_tables(&dir, &temperature_columns, &energy_columns)),
+            "Wide" => Layout::Wide(wide_tables(&dir, &[temperature_columns, energy_columns])),
+            "PerDevice" => Layout::PerDevice(DeviceTables::new(
+                dir,
+                temperature_columns,
+                energy_columns,
+                settings.close_after,
+            )),
+            _ => bail!(
+                "Layout \"{}\" does not exist. Use Long, Wide or PerDevice",
+                settings.layout
+            ),
+        };
+
         let ret = Self {
-            writer_temperature: Self::create_writer(&format!(
-                "{}/temperature.csv",
-                &settings.out_dir
-            ))
-            .chain_err(|| "Cannot open temperature outfile")?,
-            writer_energy: Self::create_writer(&format!("{}/energy.csv", &settings.out_dir))
-                .chain_err(|| "Cannot open energy outfile")?,
+            options: Options {
+                settings,
+                rotate,
+                format,
+                delimiter,
+                builder,
+                mismatch,
+            },
+            layout,
+            events,
         };
         Ok(ret)
     }
 
-    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
-        self.write_temperature(&when, data)?;
-        self.write_energy(&when, data)?;
-        Ok(())
+    /// The wide layout leaves the cells of missing devices empty.
+    fn needs_snapshots(&self) -> bool {
+        matches!(self.layout, Layout::Wide(_))
     }
-}
 
-impl Csv {
-    fn write_temperature(&mut self, when: &Duration, data: &[Device]) -> Result<()> {
-        let timestamp = when.as_secs();
-        let records = data.iter().filter_map(|d| {
-            let temperature = match &d.temperature {
-                Some(ref temperature) => temperature,
-                _ => return None,
-            };
-
-            let record = RecordTemperature {
-                timestamp,
-                id: &d.common.unique_id,
-                temperature: temperature.temperature,
-                offset: temperature.offset,
-            };
-            Some(record)
-        });
-
-        for record in records {
-            self.writer_temperature
-                .serialize(record)
-                .chain_err(|| "Error serializing csv record")?;
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        let secs = i64::try_from(when.as_secs()).chain_err(|| "Timestamp out of range")?;
+        match &mut self.layout {
+            Layout::Long(tables) => {
+                for table in tables {
+                    table
+                        .write(&self.options, secs, data)
+                        .chain_err(|| format!("Cannot write {} outfile", table.name()))?;
+                }
+            }
+            Layout::Wide(tables) => {
+                for table in tables {
+                    table
+                        .write(&self.options, secs, data)
+                        .chain_err(|| format!("Cannot write {} outfile", table.name()))?;
+                }
+            }
+            Layout::PerDevice(tables) => tables.write(&self.options, secs, data)?,
         }
-        self.writer_temperature
-            .flush()
-            .chain_err(|| "Cannot flush out csv records")?;
-
         Ok(())
     }
 
-    fn write_energy(&mut self, when: &Duration, data: &[Device]) -> Result<()> {
-        let timestamp = when.as_secs();
-        let records = data.iter().filter_map(|d| {
-            let energy = match &d.powermeter {
-                Some(energy) => energy,
-                _ => return None,
-            };
-
-            let record = RecordEnergy {
-                timestamp,
-                id: &d.common.unique_id,
-                voltage: energy.voltage,
-                power: energy.power,
-            };
-            Some(record)
-        });
-
-        for record in records {
-            self.writer_energy
-                .serialize(record)
-                .chain_err(|| "Error serializing csv record")?;
-        }
-        self.writer_energy
-            .flush()
-            .chain_err(|| "Cannot flush out csv records")?;
-
-        Ok(())
+    fn event(&mut self, when: Duration, events: &[Event]) -> Result<()> {
+        let secs = i64::try_from(when.as_secs()).chain_err(|| "Timestamp out of range")?;
+        self.events
+            .write(&self.options, secs, events)
+            .chain_err(|| "Cannot write events outfile")
     }
+}
 
-    fn create_writer(path: &str) -> std::result::Result<Writer<File>, std::io::Error> {
-        let mut fbuilder = OpenOptions::new();
-        let file_prexists;
+fn parse_columns(name: &str, columns: &[String]) -> Result<Vec<Column>> {
+    let columns = columns
+        .iter()
+        .map(|c| Column::parse(c))
+        .collect::<Result<Vec<_>>>()?;
+    if columns.is_empty() {
+        bail!("The {} outfile needs at least one column", name);
+    }
+    Ok(columns)
+}
 
-        fbuilder.read(false).append(true).truncate(false);
-        let file = match fbuilder.create(false).open(path) {
-            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {
-                file_prexists = false;
-                fbuilder.create(true).open(path)
-            }
-            x @ Ok(_) => {
-                file_prexists = true;
-                x
-            }
-            x => {
-                file_prexists = false;
-                x
+/// Creates a wide table for every reading in the column lists. The time
+/// columns of the list the reading was found in lead each row.
+fn wide_tables(dir: &Path, lists: &[Vec<Column>]) -> Vec<WideTable> {
+    let mut tables: Vec<WideTable> = Vec::new();
+    for columns in lists {
+        let mut time_columns: Vec<_> = columns.iter().copied().filter(|c| c.is_time()).collect();
+        if time_columns.is_empty() {
+            time_columns.push(Column::Timestamp);
+        }
+        for column in columns {
+            if let Column::Reading(measurement) = column {
+                if tables.iter().all(|t| t.name() != *measurement) {
+                    tables.push(WideTable::new(
+                        dir.to_owned(),
+                        measurement,
+                        time_columns.clone(),
+                    ));
+                }
             }
-        }?;
-        Ok(WriterBuilder::new()
-            .has_headers(!file_prexists)
-            .from_writer(file))
+        }
     }
+    tables
 }
@@ -0,0 +1,193 @@
+use crate::device::{Device, Recorded, READINGS};
+use crate::errors::*;
+
+use chrono::{FixedOffset, Local, TimeZone, Utc};
+use error_chain::bail;
+
+const ISO_8601: &str = "%Y-%m-%dT%H:%M:%S%:z";
+
+#[derive(Clone, Copy)]
+pub enum Column {
+    Timestamp,
+    IsoTime,
+    Ain,
+    /// Same as `Ain`. The header of files written by older versions.
+    Id,
+    Name,
+    Alias,
+    Room,
+    Tags,
+    Product,
+    Reading(&'static str),
+}
+
+impl Column {
+    pub fn parse(name: &str) -> Result<Self> {
+        let column = match name {
+            "timestamp" => Column::Timestamp,
+            "iso_time" => Column::IsoTime,
+            "ain" => Column::Ain,
+            "id" => Column::Id,
+            "name" => Column::Name,
+            "alias" => Column::Alias,
+            "room" => Column::Room,
+            "tags" => Column::Tags,
+            "product" => Column::Product,
+            _ => match READINGS.iter().find(|r| **r == name) {
+                Some(reading) => Column::Reading(reading),
+                None => bail!(
+                    "Column \"{}\" does not exist. Use timestamp, iso_time, ain, id, name, alias, room, tags, product or one of {:?}",
+                    name,
+                    READINGS
+                ),
+            },
+        };
+        Ok(column)
+    }
+
+    pub fn is_time(self) -> bool {
+        matches!(self, Column::Timestamp | Column::IsoTime)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Timestamp => "timestamp",
+            Column::IsoTime => "iso_time",
+            Column::Ain => "ain",
+            Column::Id => "id",
+            Column::Name => "name",
+            Column::Alias => "alias",
+            Column::Room => "room",
+            Column::Tags => "tags",
+            Column::Product => "product",
+            Column::Reading(reading) => reading,
+        }
+    }
+}
+
+pub enum Zone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl Zone {
+    /// Accepts Utc, Local or a fixed offset like "+01:00".
+    pub fn parse(zone: &str) -> Result<Self> {
+        match zone {
+            "Utc" => return Ok(Zone::Utc),
+            "Local" => return Ok(Zone::Local),
+            _ => (),
+        }
+
+        let invalid = || {
+            format!(
+                "Timezone \"{}\" does not exist. Use Utc, Local or an offset like +01:00",
+                zone
+            )
+        };
+        let (sign, rest) = match zone.chars().next() {
+            Some('+') => (1, &zone[1..]),
+            Some('-') => (-1, &zone[1..]),
+            _ => bail!(invalid()),
+        };
+        let mut parts = rest.splitn(2, ':');
+        let hours: i32 = parts.next().unwrap_or("").parse().chain_err(invalid)?;
+        let minutes: i32 = parts.next().unwrap_or("0").parse().chain_err(invalid)?;
+        let offset =
+            FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).chain_err(invalid)?;
+        Ok(Zone::Fixed(offset))
+    }
+
+    pub fn format(&self, secs: i64, format: &str) -> Result<String> {
+        let formatted = match self {
+            Zone::Utc => Utc
+                .timestamp_opt(secs, 0)
+                .single()
+                .map(|t| t.format(format).to_string()),
+            Zone::Local => Local
+                .timestamp_opt(secs, 0)
+                .single()
+                .map(|t| t.format(format).to_string()),
+            Zone::Fixed(offset) => offset
+                .timestamp_opt(secs, 0)
+                .single()
+                .map(|t| t.format(format).to_string()),
+        };
+        formatted.chain_err(|| format!("Timestamp {} out of range", secs))
+    }
+}
+
+pub struct Format {
+    pub zone: Zone,
+    pub decimal_separator: String,
+    pub recorded: Recorded,
+}
+
+impl Format {
+    /// Renders a single row. Readings the device does not have stay empty.
+    pub fn record(&self, columns: &[Column], secs: i64, device: &Device) -> Result<Vec<String>> {
+        let readings = device.readings(self.recorded);
+        columns
+            .iter()
+            .map(|column| {
+                let value = match column {
+                    Column::Timestamp | Column::IsoTime => self.time(*column, secs)?,
+                    Column::Ain | Column::Id => device.common.unique_id.clone(),
+                    Column::Name => device.common.name.clone(),
+                    Column::Alias => device.common.alias.clone(),
+                    Column::Room => device.common.room.clone(),
+                    Column::Tags => device.common.tags.join(","),
+                    Column::Product => device.common.productname.clone(),
+                    Column::Reading(name) => self.reading(&readings, name),
+                };
+                Ok(value)
+            })
+            .collect()
+    }
+
+    /// Renders the columns that only depend on the time. Others stay empty.
+    pub fn time(&self, column: Column, secs: i64) -> Result<String> {
+        match column {
+            Column::Timestamp => Ok(secs.to_string()),
+            Column::IsoTime => self.zone.format(secs, ISO_8601),
+            _ => Ok(String::new()),
+        }
+    }
+
+    pub fn reading(&self, readings: &[(&str, f64)], name: &str) -> String {
+        readings
+            .iter()
+            .find(|(reading, _)| *reading == name)
+            .map_or_else(String::new, |(_, value)| self.number(&value.to_string()))
+    }
+
+    fn number(&self, value: &str) -> String {
+        value.replace('.', &self.decimal_separator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offset(zone: &str) -> String {
+        Zone::parse(zone).unwrap().format(0, "%:z").unwrap()
+    }
+
+    #[test]
+    fn parses_zones() {
+        assert_eq!(offset("Utc"), "+00:00");
+        assert_eq!(offset("+01:00"), "+01:00");
+        assert_eq!(offset("+5:30"), "+05:30");
+        assert_eq!(offset("-03"), "-03:00");
+        assert!(Zone::parse("Local").is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_zones() {
+        for zone in &["", "utc", "01:00", "+1:xx", "+25:00", "Europe/Berlin"] {
+            assert!(Zone::parse(zone).is_err(), "{}", zone);
+        }
+    }
+}
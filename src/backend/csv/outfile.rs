@@ -0,0 +1,298 @@
+use super::{Mismatch, Options, Rotate};
+use crate::backend::rotation::{compress, remove_expired};
+use crate::errors::*;
+use crate::print_errors;
+
+use csv::{ReaderBuilder, Writer};
+use error_chain::bail;
+
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A csv file that is rotated according to the settings. The header is
+/// checked against existing files before appending to them.
+pub struct OutFile {
+    dir: PathBuf,
+    name: &'static str,
+    header: Vec<String>,
+    current: Option<Current>,
+}
+
+struct Current {
+    period: String,
+    path: PathBuf,
+    writer: Writer<File>,
+}
+
+impl OutFile {
+    pub fn new(dir: PathBuf, name: &'static str, header: Vec<String>) -> Self {
+        Self {
+            dir,
+            name,
+            header,
+            current: None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Closes the current file. The next write goes through the header
+    /// mismatch handling.
+    pub fn set_header(&mut self, header: Vec<String>) {
+        self.header = header;
+        self.current = None;
+    }
+
+    pub fn writer(&mut self, options: &Options, secs: i64) -> Result<&mut Writer<File>> {
+        self.open(options, secs)?;
+        Ok(&mut self
+            .current
+            .as_mut()
+            .expect("Outfile was just opened.")
+            .writer)
+    }
+
+    fn open(&mut self, options: &Options, secs: i64) -> Result<()> {
+        let zone = &options.format.zone;
+        let period = match options.rotate {
+            Rotate::Daily => zone.format(secs, "%Y-%m-%d")?,
+            Rotate::Monthly => zone.format(secs, "%Y-%m")?,
+            Rotate::None | Rotate::Size => String::new(),
+        };
+
+        let expired = match &self.current {
+            Some(current) if options.rotate == Rotate::Size => {
+                let size = current
+                    .writer
+                    .get_ref()
+                    .metadata()
+                    .chain_err(|| "Cannot stat outfile")?
+                    .len();
+                size >= options.settings.max_size
+            }
+            Some(current) => current.period != period,
+            None => true,
+        };
+        if expired {
+            self.rotate(options, period, secs)?;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self, options: &Options, period: String, secs: i64) -> Result<()> {
+        let settings = &options.settings;
+        let dir = &self.dir;
+        if let Some(old) = self.current.take() {
+            let mut path = old.path;
+            drop(old.writer);
+            if options.rotate == Rotate::Size {
+                let rotated = dir.join(format!(
+                    "{}-{}.csv",
+                    self.name,
+                    options.format.zone.format(secs, "%Y-%m-%dT%H-%M-%S")?
+                ));
+                fs::rename(&path, &rotated)
+                    .chain_err(|| format!("Cannot rotate {}", path.display()))?;
+                path = rotated;
+            }
+            if settings.compress {
+                compress(&path).chain_err(|| format!("Cannot compress {}", path.display()))?;
+            }
+        }
+
+        if settings.retention > 0 {
+            let max_age = Duration::from_secs(settings.retention * 24 * 60 * 60);
+            if let Err(e) = remove_expired(dir, self.name, "csv", max_age) {
+                print_errors(Error::with_chain(
+                    e,
+                    "Backend Csv: Cannot remove expired files",
+                ));
+            }
+        }
+
+        let path = if period.is_empty() {
+            dir.join(format!("{}.csv", self.name))
+        } else {
+            dir.join(format!("{}-{}.csv", self.name, period))
+        };
+        let writer = self
+            .create_writer(options, &path)
+            .chain_err(|| format!("Cannot open outfile {}", path.display()))?;
+        self.current = Some(Current {
+            period,
+            path,
+            writer,
+        });
+        Ok(())
+    }
+
+    fn create_writer(&self, options: &Options, path: &Path) -> Result<Writer<File>> {
+        let header = &self.header;
+        let mut write_header = true;
+        if let Some(existing) = read_header(path, options.delimiter)? {
+            if existing == *header {
+                write_header = false;
+            } else {
+                match options.mismatch {
+                    Mismatch::Rotate => {
+                        move_aside(path)?;
+                    }
+                    // e.g. after a change of the delimiter
+                    Mismatch::Rewrite if !shares_columns(&existing, header) => {
+                        let aside = move_aside(path)?;
+                        print_errors(Error::from(format!(
+                            "Backend Csv: {} has no columns in common with the configured ones. Moved it to {}",
+                            path.display(),
+                            aside.display()
+                        )));
+                    }
+                    Mismatch::Rewrite => {
+                        rewrite(options, path, header)
+                            .chain_err(|| "Cannot rewrite outdated file")?;
+                        write_header = false;
+                    }
+                    Mismatch::Fail => bail!(
+                        "Header {:?} does not match the configured columns {:?}",
+                        existing,
+                        header
+                    ),
+                }
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .chain_err(|| "Cannot open file")?;
+        let mut writer = options.builder.from_writer(file);
+        if write_header {
+            writer
+                .write_record(header)
+                .chain_err(|| "Cannot write csv header")?;
+        }
+        Ok(writer)
+    }
+}
+
+/// Returns `None` if there is no file or it is empty.
+fn read_header(path: &Path, delimiter: u8) -> Result<Option<Vec<String>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(Error::with_chain(err, "Cannot open file")),
+    };
+    let mut reader = ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(file);
+    match reader.records().next() {
+        Some(record) => {
+            let record = record.chain_err(|| "Cannot read csv header")?;
+            Ok(Some(record.iter().map(ToOwned::to_owned).collect()))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Rewriting only makes sense if the timestamps survive it or, without
+/// them, at least one column does.
+fn shares_columns(old: &[String], new: &[String]) -> bool {
+    let shared = |name: &String| old.contains(name);
+    match new.iter().find(|name| *name == "timestamp") {
+        Some(timestamp) => shared(timestamp),
+        None => new.iter().any(shared),
+    }
+}
+
+fn move_aside(path: &Path) -> Result<PathBuf> {
+    let aside = aside(path);
+    fs::rename(path, &aside)
+        .chain_err(|| format!("Cannot move outdated file to {}", aside.display()))?;
+    Ok(aside)
+}
+
+/// Finds a free name next to the file, e.g. `temperature.1.csv`.
+fn aside(path: &Path) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .expect("Path was built from a file name.")
+        .to_string_lossy();
+    let mut n = 1;
+    loop {
+        let aside = path.with_file_name(format!("{}.{}.csv", stem, n));
+        if !aside.exists() {
+            return aside;
+        }
+        n += 1;
+    }
+}
+
+/// Rewrites the file with the new header. Columns are matched by name and
+/// left empty if the old file does not have them.
+fn rewrite(options: &Options, path: &Path, header: &[String]) -> Result<()> {
+    let mut reader = ReaderBuilder::new()
+        .delimiter(options.delimiter)
+        .flexible(true)
+        .from_path(path)
+        .chain_err(|| "Cannot open file")?;
+    let old_header = reader
+        .headers()
+        .chain_err(|| "Cannot read csv header")?
+        .clone();
+    let indices: Vec<_> = header
+        .iter()
+        .map(|name| old_header.iter().position(|old| old == name))
+        .collect();
+
+    let file_name = path
+        .file_name()
+        .expect("Path was built from a file name.")
+        .to_string_lossy();
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    let mut writer = options
+        .builder
+        .from_path(&tmp_path)
+        .chain_err(|| format!("Cannot create {}", tmp_path.display()))?;
+    writer
+        .write_record(header)
+        .chain_err(|| "Cannot write csv header")?;
+    for record in reader.records() {
+        let record = record.chain_err(|| "Cannot read csv record")?;
+        let row = indices
+            .iter()
+            .map(|index| index.and_then(|i| record.get(i)).unwrap_or(""));
+        writer
+            .write_record(row)
+            .chain_err(|| "Error writing csv record")?;
+    }
+    writer
+        .flush()
+        .chain_err(|| "Cannot flush out csv records")?;
+    drop(writer);
+
+    fs::rename(&tmp_path, path).chain_err(|| format!("Cannot move {} into place", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(line: &str) -> Vec<String> {
+        line.split(',').map(ToOwned::to_owned).collect()
+    }
+
+    #[test]
+    fn rewrites_only_with_shared_columns() {
+        let new = header("timestamp,ain,temperature");
+        assert!(shares_columns(&header("timestamp,id,temperature"), &new));
+        // read with the wrong delimiter
+        assert!(!shares_columns(&header("timestamp;ain;temperature"), &new));
+        assert!(!shares_columns(&header("ain,temperature"), &new));
+        assert!(shares_columns(&header("isotime,ain"), &header("ain,power")));
+    }
+}
@@ -0,0 +1,258 @@
+use super::format::Column;
+use super::outfile::OutFile;
+use super::Options;
+use crate::backend::plaintext::sanitize;
+use crate::device::{Device, Recorded};
+use crate::errors::*;
+use crate::inventory::Event;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+/// The temperature and energy tables of the long layout.
+pub fn long_tables(dir: &Path, temperature: &[Column], energy: &[Column]) -> Vec<LongTable> {
+    vec![
+        LongTable::new(dir.to_owned(), "temperature", temperature.to_vec(), |d| {
+            d.temperature.is_some()
+        }),
+        LongTable::new(dir.to_owned(), "energy", energy.to_vec(), |d| {
+            d.powermeter.is_some()
+        }),
+    ]
+}
+
+/// One row per device and poll.
+pub struct LongTable {
+    out: OutFile,
+    columns: Vec<Column>,
+    has: fn(&Device) -> bool,
+}
+
+impl LongTable {
+    pub fn new(
+        dir: PathBuf,
+        name: &'static str,
+        columns: Vec<Column>,
+        has: fn(&Device) -> bool,
+    ) -> Self {
+        let header = columns.iter().map(|c| c.name().to_owned()).collect();
+        Self {
+            out: OutFile::new(dir, name, header),
+            columns,
+            has,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.out.name()
+    }
+
+    pub fn write(&mut self, options: &Options, secs: i64, data: &[Device]) -> Result<()> {
+        // Devices without the readings must not even create the file.
+        let has = self.has;
+        let matching: Vec<_> = data.iter().filter(|d| has(d)).collect();
+        if matching.is_empty() {
+            return Ok(());
+        }
+        let writer = self.out.writer(options, secs)?;
+        for device in matching {
+            writer
+                .write_record(options.format.record(&self.columns, secs, device)?)
+                .chain_err(|| "Error writing csv record")?;
+        }
+        writer.flush().chain_err(|| "Cannot flush out csv records")
+    }
+}
+
+/// One row per change of the device inventory, independent of the layout.
+pub struct EventTable {
+    out: OutFile,
+}
+
+impl EventTable {
+    pub fn new(dir: PathBuf) -> Self {
+        let header = [
+            "timestamp",
+            "iso_time",
+            "ain",
+            "name",
+            "event",
+            "old",
+            "new",
+        ]
+        .iter()
+        .map(|c| (*c).to_owned())
+        .collect();
+        Self {
+            out: OutFile::new(dir, "events", header),
+        }
+    }
+
+    pub fn write(&mut self, options: &Options, secs: i64, events: &[Event]) -> Result<()> {
+        let writer = self.out.writer(options, secs)?;
+        for event in events {
+            writer
+                .write_record(&[
+                    options.format.time(Column::Timestamp, secs)?,
+                    options.format.time(Column::IsoTime, secs)?,
+                    event.ain.clone(),
+                    event.name.clone(),
+                    event.kind.name().to_owned(),
+                    event.old.clone(),
+                    event.new.clone(),
+                ])
+                .chain_err(|| "Error writing csv record")?;
+        }
+        writer.flush().chain_err(|| "Cannot flush out csv records")
+    }
+}
+
+/// One row per poll with a column per device.
+pub struct WideTable {
+    out: OutFile,
+    time_columns: Vec<Column>,
+    measurement: &'static str,
+    // AIN and column label of every device seen so far
+    devices: Vec<(String, String)>,
+}
+
+impl WideTable {
+    pub fn new(dir: PathBuf, measurement: &'static str, time_columns: Vec<Column>) -> Self {
+        let header = time_columns.iter().map(|c| c.name().to_owned()).collect();
+        Self {
+            out: OutFile::new(dir, measurement, header),
+            time_columns,
+            measurement,
+            devices: Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.measurement
+    }
+
+    pub fn write(&mut self, options: &Options, secs: i64, data: &[Device]) -> Result<()> {
+        // A new device extends the header which moves the file on according
+        // to the header mismatch setting.
+        if self.add_devices(data, options.format.recorded) {
+            let header = self
+                .time_columns
+                .iter()
+                .map(|c| c.name().to_owned())
+                .chain(self.devices.iter().map(|(_, label)| label.clone()))
+                .collect();
+            self.out.set_header(header);
+        }
+        if self.devices.is_empty() {
+            return Ok(());
+        }
+
+        let mut row: Vec<_> = self
+            .time_columns
+            .iter()
+            .map(|c| options.format.time(*c, secs))
+            .collect::<Result<_>>()?;
+        for (ain, _) in &self.devices {
+            let value = data
+                .iter()
+                .find(|d| d.common.unique_id == *ain)
+                .map_or_else(String::new, |d| {
+                    options
+                        .format
+                        .reading(&d.readings(options.format.recorded), self.measurement)
+                });
+            row.push(value);
+        }
+
+        let writer = self.out.writer(options, secs)?;
+        writer
+            .write_record(&row)
+            .chain_err(|| "Error writing csv record")?;
+        writer.flush().chain_err(|| "Cannot flush out csv records")
+    }
+
+    fn add_devices(&mut self, data: &[Device], recorded: Recorded) -> bool {
+        let mut added = false;
+        for device in data {
+            let common = &device.common;
+            let known = self.devices.iter().any(|(ain, _)| *ain == common.unique_id);
+            let has = device
+                .readings(recorded)
+                .iter()
+                .any(|(reading, _)| *reading == self.measurement);
+            if known || !has {
+                continue;
+            }
+
+            let label = if self
+                .devices
+                .iter()
+                .any(|(_, label)| label == common.label())
+            {
+                format!("{} ({})", common.label(), common.unique_id)
+            } else {
+                common.label().to_owned()
+            };
+            self.devices.push((common.unique_id.clone(), label));
+            added = true;
+        }
+        added
+    }
+}
+
+/// Long tables in a directory per device. Files are opened when a device
+/// is first seen and closed again once it was absent for a while.
+pub struct DeviceTables {
+    dir: PathBuf,
+    temperature: Vec<Column>,
+    energy: Vec<Column>,
+    close_after: u64,
+    // tables and the time the device was last seen by AIN
+    open: HashMap<String, (i64, Vec<LongTable>)>,
+}
+
+impl DeviceTables {
+    pub fn new(
+        dir: PathBuf,
+        temperature: Vec<Column>,
+        energy: Vec<Column>,
+        close_after: u64,
+    ) -> Self {
+        Self {
+            dir,
+            temperature,
+            energy,
+            close_after,
+            open: HashMap::new(),
+        }
+    }
+
+    pub fn write(&mut self, options: &Options, secs: i64, data: &[Device]) -> Result<()> {
+        for device in data.iter().filter(|d| d.common.present) {
+            let ain = &device.common.unique_id;
+            if !self.open.contains_key(ain) {
+                let dir = self.dir.join(sanitize(ain));
+                fs::create_dir_all(&dir)
+                    .chain_err(|| format!("Cannot create directory {}", dir.display()))?;
+                let tables = long_tables(&dir, &self.temperature, &self.energy);
+                self.open.insert(ain.clone(), (secs, tables));
+            }
+
+            let (seen, tables) = self.open.get_mut(ain).expect("Tables were just opened.");
+            *seen = secs;
+            for table in tables {
+                table
+                    .write(options, secs, slice::from_ref(device))
+                    .chain_err(|| format!("Cannot write {} outfile of {}", table.name(), ain))?;
+            }
+        }
+
+        let close_after = self.close_after;
+        self.open
+            .retain(|_, (seen, _)| u64::try_from(secs - *seen).unwrap_or(0) < close_after);
+        Ok(())
+    }
+}
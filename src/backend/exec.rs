@@ -0,0 +1,201 @@
+use super::backoff::Backoff;
+use super::Backend;
+use crate::device::{Device, Output, Recorded};
+use crate::errors::*;
+use crate::{print_errors, settings};
+
+use config::Value;
+use error_chain::bail;
+use serde::{Deserialize, Serialize};
+
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    command: String,
+    args: Vec<String>,
+    min_backoff: u64,
+    max_backoff: u64,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Exec> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("command".into(), "".into()),
+            ("args".into(), Vec::<String>::new().into()),
+            ("min_backoff".into(), 1.into()),
+            ("max_backoff".into(), 300.into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+pub struct Exec {
+    settings: Settings,
+    child: Option<Child>,
+    backoff: Backoff,
+    recorded: Recorded,
+}
+
+#[derive(Serialize)]
+struct Poll<'a> {
+    timestamp: u64,
+    devices: Vec<Output<'a>>,
+}
+
+impl<'de> Backend<'de> for Exec {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Exec"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        if settings.command.is_empty() {
+            bail!("No command configured");
+        }
+        let mut ret = Self {
+            backoff: Backoff::new(settings.min_backoff, settings.max_backoff),
+            settings,
+            child: None,
+            recorded,
+        };
+        ret.child = Some(ret.spawn()?);
+        Ok(ret)
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        let poll = Poll {
+            timestamp: when.as_secs(),
+            devices: Output::all(data, self.recorded),
+        };
+        let mut line = serde_json::to_vec(&poll).chain_err(|| "Cannot serialize devices")?;
+        line.push(b'\n');
+
+        self.ensure_running()?;
+        let stdin = self
+            .child
+            .as_mut()
+            .and_then(|child| child.stdin.as_mut())
+            .expect("Child is running with piped stdin.");
+
+        match stdin.write_all(&line).and_then(|()| stdin.flush()) {
+            Ok(()) => {
+                self.backoff.succeeded();
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(mut child) = self.child.take() {
+                    // it might still be alive but ignores its stdin
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                self.backoff.failed();
+                Err(Error::with_chain(e, "Cannot write to command"))
+            }
+        }
+    }
+}
+
+impl Exec {
+    fn spawn(&self) -> Result<Child> {
+        let mut child = Command::new(&self.settings.command)
+            .args(&self.settings.args)
+            .stdin(Stdio::piped())
+            // would be mixed up with the output of the Console backend
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .chain_err(|| format!("Cannot start command \"{}\"", self.settings.command))?;
+
+        let stderr = child.stderr.take().expect("Stderr is piped.");
+        thread::Builder::new()
+            .name("backend-Exec-stderr".into())
+            .spawn(move || Self::forward_stderr(stderr))
+            .chain_err(|| "Cannot start thread forwarding stderr of command")?;
+
+        Ok(child)
+    }
+
+    fn forward_stderr(stderr: ChildStderr) {
+        for line in BufReader::new(stderr).lines() {
+            match line {
+                Ok(line) => print_errors(Error::from(format!("Backend Exec: {}", line))),
+                Err(_) => break,
+            }
+        }
+    }
+
+    fn ensure_running(&mut self) -> Result<()> {
+        if let Some(child) = &mut self.child {
+            match child.try_wait() {
+                Ok(None) => return Ok(()),
+                // the sample goes to its successor
+                Ok(Some(status)) => {
+                    self.child = None;
+                    print_errors(Error::from(format!(
+                        "Backend Exec: Command exited with {}. Restarting it",
+                        status
+                    )));
+                }
+                Err(e) => {
+                    self.child = None;
+                    self.backoff.failed();
+                    return Err(Error::with_chain(e, "Cannot query state of command"));
+                }
+            }
+        }
+
+        if let Some(remaining) = self.backoff.remaining() {
+            bail!("Command is restarted in {:?}. Dropped sample", remaining);
+        }
+
+        match self.spawn() {
+            Ok(child) => {
+                self.child = Some(child);
+                Ok(())
+            }
+            Err(e) => {
+                self.backoff.failed();
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::tests::device;
+
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn restarts_exited_command_for_sample() {
+        let out = std::env::temp_dir().join(format!("fritzlogger-exec-{}", process::id()));
+        let _ = fs::remove_file(&out);
+        let mut exec = Exec::new(Settings {
+            command: "sh".to_owned(),
+            args: vec!["-c".to_owned(), format!("head -n 1 >> {}", out.display())],
+            min_backoff: 1,
+            max_backoff: 1,
+            temperature: "Both".to_owned(),
+        })
+        .unwrap();
+
+        exec.log(Duration::from_secs(1), &[device("1")]).unwrap();
+        exec.child.as_mut().unwrap().wait().unwrap();
+        exec.log(Duration::from_secs(2), &[device("1")]).unwrap();
+        exec.child.as_mut().unwrap().wait().unwrap();
+
+        let lines = fs::read_to_string(&out).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        fs::remove_file(&out).unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+use super::backoff::Backoff;
+use super::plaintext::{datagrams, sanitize};
+use super::Backend;
+use crate::device::{Device, Recorded};
+use crate::errors::*;
+use crate::{print_errors, settings};
+
+use config::Value;
+use error_chain::bail;
+use serde::{Deserialize, Serialize};
+
+use std::fmt::Write as _;
+use std::io::Write;
+use std::net::{TcpStream, UdpSocket};
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    host: String,
+    port: u16,
+    protocol: String,
+    prefix: String,
+    path: String,
+    timeout: u64,
+    min_backoff: u64,
+    max_backoff: u64,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Graphite> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("host".into(), "localhost".into()),
+            ("port".into(), 2003.into()),
+            ("protocol".into(), "Tcp".into()),
+            ("prefix".into(), "fritz".into()),
+            ("path".into(), "{name}".into()),
+            ("timeout".into(), 10.into()),
+            ("min_backoff".into(), 1.into()),
+            ("max_backoff".into(), 300.into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+pub struct Graphite {
+    settings: Settings,
+    connection: Option<Connection>,
+    backoff: Backoff,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Graphite {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Graphite"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        if settings.protocol != "Tcp" && settings.protocol != "Udp" {
+            bail!(
+                "Protocol \"{}\" does not exist. Use Tcp or Udp",
+                settings.protocol
+            );
+        }
+        let mut ret = Self {
+            backoff: Backoff::new(settings.min_backoff, settings.max_backoff),
+            settings,
+            connection: None,
+            recorded,
+        };
+        match ret.connect() {
+            Ok(connection) => ret.connection = Some(connection),
+            Err(e) => {
+                ret.backoff.failed();
+                print_errors(Error::with_chain(
+                    e,
+                    "Backend Graphite: Carbon not ready. Retrying on next poll",
+                ));
+            }
+        }
+        Ok(ret)
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        let lines = self.to_lines(when.as_secs(), data);
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        if self.connection.is_none() {
+            if let Some(remaining) = self.backoff.remaining() {
+                bail!("Reconnecting to carbon in {:?}. Dropped sample", remaining);
+            }
+            match self.connect() {
+                Ok(connection) => self.connection = Some(connection),
+                Err(e) => {
+                    self.backoff.failed();
+                    return Err(e);
+                }
+            }
+        }
+
+        let result = match self.connection.as_mut().expect("Connection is open.") {
+            Connection::Tcp(stream) => stream
+                .write_all(lines.as_bytes())
+                .and_then(|()| stream.flush()),
+            Connection::Udp(socket) => {
+                datagrams(&lines).try_for_each(|d| socket.send(d.as_bytes()).map(|_| ()))
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                self.backoff.succeeded();
+                Ok(())
+            }
+            Err(e) => {
+                self.connection = None;
+                self.backoff.failed();
+                Err(Error::with_chain(e, "Cannot send metrics to carbon"))
+            }
+        }
+    }
+}
+
+impl Graphite {
+    fn connect(&self) -> Result<Connection> {
+        let addr = (self.settings.host.as_str(), self.settings.port);
+        let timeout = Some(Duration::from_secs(self.settings.timeout));
+        let connection = if self.settings.protocol == "Udp" {
+            let socket = UdpSocket::bind("0.0.0.0:0")
+                .and_then(|socket| socket.connect(addr).map(|()| socket))
+                .chain_err(|| "Cannot open udp socket")?;
+            Connection::Udp(socket)
+        } else {
+            let stream = TcpStream::connect(addr)
+                .and_then(|stream| stream.set_write_timeout(timeout).map(|()| stream))
+                .chain_err(|| {
+                    format!(
+                        "Cannot connect to {}:{}",
+                        self.settings.host, self.settings.port
+                    )
+                })?;
+            Connection::Tcp(stream)
+        };
+        Ok(connection)
+    }
+
+    fn to_lines(&self, timestamp: u64, data: &[Device]) -> String {
+        let mut lines = String::new();
+        for device in data {
+            let path = self
+                .settings
+                .path
+                .replace("{ain}", &sanitize(&device.common.unique_id))
+                .replace("{name}", &sanitize(&device.common.name))
+                .replace("{alias}", &sanitize(device.common.label()));
+            for (metric, value) in device.readings(self.recorded) {
+                writeln!(
+                    lines,
+                    "{}.{}.{} {} {}",
+                    self.settings.prefix, path, metric, value, timestamp
+                )
+                .expect("Writing to a String cannot fail.");
+            }
+        }
+        lines
+    }
+}
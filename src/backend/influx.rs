@@ -0,0 +1,338 @@
+use super::Backend;
+use crate::device::{self, Device, Recorded};
+use crate::errors::*;
+use crate::settings;
+
+use config::Value;
+use error_chain::bail;
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    target: String,
+    url: String,
+    api: String,
+    token: String,
+    org: String,
+    bucket: String,
+    database: String,
+    timeout: u64,
+    out_file: String,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Influx> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("target".into(), "Http".into()),
+            ("url".into(), "http://localhost:8086".into()),
+            ("api".into(), "v2".into()),
+            ("token".into(), "".into()),
+            ("org".into(), "".into()),
+            ("bucket".into(), "fritzlogger".into()),
+            ("database".into(), "fritzlogger".into()),
+            ("timeout".into(), 10.into()),
+            ("out_file".into(), "fritzlogger.lp".into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+enum Target {
+    Http {
+        client: Client,
+        settings: Box<Settings>,
+    },
+    File(File),
+}
+
+pub struct Influx {
+    target: Target,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Influx {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Influx"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let target = match settings.target.as_str() {
+            "Http" => {
+                if settings.api != "v1" && settings.api != "v2" {
+                    bail!("Api \"{}\" does not exist. Use v1 or v2", settings.api);
+                }
+                let client = Client::builder()
+                    .timeout(Duration::from_secs(settings.timeout))
+                    .build()
+                    .chain_err(|| "Cannot create http client")?;
+                Target::Http {
+                    client,
+                    settings: Box::new(settings),
+                }
+            }
+            "File" => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&settings.out_file)
+                    .chain_err(|| format!("Cannot open outfile {}", settings.out_file))?;
+                Target::File(file)
+            }
+            _ => bail!(
+                "Target \"{}\" does not exist. Use Http or File",
+                settings.target
+            ),
+        };
+        Ok(Self { target, recorded })
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        let lines = to_lines(when, data, self.recorded);
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        match &mut self.target {
+            Target::Http { client, settings } => {
+                let response = build_request(client, settings)
+                    .body(lines)
+                    .send()
+                    .chain_err(|| "Sending lines to influx failed")?;
+                let status = response.status();
+                if !status.is_success() {
+                    bail!("Influx rejected lines with status {}", status);
+                }
+                Ok(())
+            }
+            Target::File(file) => file
+                .write_all(lines.as_bytes())
+                .and_then(|()| file.flush())
+                .chain_err(|| "Cannot write lines to outfile"),
+        }
+    }
+}
+
+fn build_request(client: &Client, settings: &Settings) -> RequestBuilder {
+    let base = settings.url.trim_end_matches('/');
+    let request = if settings.api == "v1" {
+        client
+            .post(&format!("{}/write", base))
+            .query(&[("db", settings.database.as_str()), ("precision", "ns")])
+    } else {
+        client.post(&format!("{}/api/v2/write", base)).query(&[
+            ("org", settings.org.as_str()),
+            ("bucket", settings.bucket.as_str()),
+            ("precision", "ns"),
+        ])
+    };
+
+    if settings.token.is_empty() {
+        request
+    } else {
+        request.header("Authorization", format!("Token {}", settings.token))
+    }
+}
+
+fn to_lines(when: Duration, data: &[Device], recorded: Recorded) -> String {
+    let timestamp = when.as_nanos();
+    let mut lines = String::new();
+
+    for device in data {
+        let mut tags = String::new();
+        push_tag(&mut tags, "ain", &device.common.unique_id);
+        push_tag(&mut tags, "name", &device.common.name);
+        push_tag(&mut tags, "product", &device.common.productname);
+        push_tag(&mut tags, "alias", &device.common.alias);
+        push_tag(&mut tags, "room", &device.common.room);
+        push_tag(&mut tags, "tags", &device.common.tags.join(","));
+
+        if let Some(temperature) = &device.temperature {
+            let mut fields = Vec::new();
+            if recorded.reported() && device.changed("temperature") {
+                fields.push(format!("temperature={}", field(temperature.temperature)));
+            }
+            if recorded.sensor() && device.changed("sensor_temperature") {
+                fields.push(format!("sensor={}", field(temperature.sensor)));
+            }
+            if device.changed("offset") {
+                fields.push(format!("offset={}", field(temperature.offset)));
+            }
+            push_line(&mut lines, "temperature", &tags, &fields, timestamp);
+        }
+
+        if let Some(powermeter) = &device.powermeter {
+            let mut fields = Vec::new();
+            if device.changed("voltage") {
+                fields.push(format!("voltage={}", field(powermeter.voltage)));
+            }
+            if device.changed("power") {
+                fields.push(format!("power={}", field(powermeter.power)));
+            }
+            if device.changed("energy") {
+                fields.push(format!("energy={}", field(powermeter.energy)));
+            }
+            push_line(&mut lines, "powermeter", &tags, &fields, timestamp);
+        }
+    }
+
+    lines
+}
+
+/// Lines without fields are invalid, e.g. when none of them changed.
+fn push_line(
+    lines: &mut String,
+    measurement: &str,
+    tags: &str,
+    fields: &[String],
+    timestamp: u128,
+) {
+    if fields.is_empty() {
+        return;
+    }
+    writeln!(
+        lines,
+        "{}{} {} {}",
+        measurement,
+        tags,
+        fields.join(","),
+        timestamp
+    )
+    .expect("Writing to a String cannot fail.");
+}
+
+/// Raw units stay integers so that series written by older versions keep
+/// their field type.
+fn field<T: fmt::Display>(value: T) -> String {
+    if device::raw_units() {
+        format!("{}i", value)
+    } else {
+        value.to_string()
+    }
+}
+
+fn push_tag(tags: &mut String, key: &str, value: &str) {
+    // influx rejects empty tag values
+    if value.is_empty() {
+        return;
+    }
+    tags.push(',');
+    tags.push_str(key);
+    tags.push('=');
+    for c in value.chars() {
+        if c == ',' || c == '=' || c == ' ' || c == '\\' {
+            tags.push('\\');
+        }
+        tags.push(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stand_in;
+    use crate::device::tests::device;
+
+    fn influx(url: String, api: &str) -> Influx {
+        let settings = Settings {
+            target: "Http".to_owned(),
+            url,
+            api: api.to_owned(),
+            token: "secret".to_owned(),
+            org: "home".to_owned(),
+            bucket: "fritz".to_owned(),
+            database: "fritzdb".to_owned(),
+            timeout: 5,
+            out_file: String::new(),
+            temperature: "Both".to_owned(),
+        };
+        Influx::new(settings).unwrap()
+    }
+
+    #[test]
+    fn posts_lines() {
+        let (url, requests) = stand_in::serve(&[204, 204]);
+        let data = [device("1")];
+
+        let mut v2 = influx(url.clone(), "v2");
+        v2.log(Duration::from_secs(2), &data).unwrap();
+        let request = requests.recv().unwrap();
+        assert_eq!(
+            request.head[0],
+            "POST /api/v2/write?org=home&bucket=fritz&precision=ns HTTP/1.1"
+        );
+        assert_eq!(request.header("authorization"), Some("Token secret"));
+        assert_eq!(
+            request.body,
+            to_lines(Duration::from_secs(2), &data, Recorded::Both)
+        );
+
+        let mut v1 = influx(url + "/", "v1");
+        v1.log(Duration::from_secs(2), &data).unwrap();
+        let request = requests.recv().unwrap();
+        assert_eq!(
+            request.head[0],
+            "POST /write?db=fritzdb&precision=ns HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn fails_on_rejected_lines() {
+        let (url, requests) = stand_in::serve(&[400, 500]);
+        let mut influx = influx(url, "v2");
+        for _ in 0..2 {
+            assert!(influx.log(Duration::from_secs(2), &[device("1")]).is_err());
+            requests.recv().unwrap();
+        }
+        // nothing to send for no devices
+        influx.log(Duration::from_secs(2), &[]).unwrap();
+    }
+
+    #[test]
+    fn fails_without_server() {
+        let (url, _) = stand_in::serve(&[]);
+        let mut influx = influx(url, "v2");
+        assert!(influx.log(Duration::from_secs(2), &[device("1")]).is_err());
+    }
+
+    #[test]
+    fn escapes_tags() {
+        let mut tags = String::new();
+        push_tag(&mut tags, "name", "Living room, a=b\\c");
+        push_tag(&mut tags, "room", "");
+        assert_eq!(tags, ",name=Living\\ room\\,\\ a\\=b\\\\c");
+    }
+
+    #[test]
+    fn lines_per_measurement() {
+        let mut desk = device("08761 0000434");
+        desk.common.tags = vec!["a".to_owned(), "b".to_owned()];
+        let lines = to_lines(Duration::from_secs(2), &[desk], Recorded::Both);
+        assert_eq!(
+            lines,
+            concat!(
+                r"temperature,ain=08761\ 0000434,name=Desk,product=FRITZ!DECT\ 200,tags=a\,b ",
+                "temperature=21.5,sensor=22,offset=-0.5 2000000000\n",
+                r"powermeter,ain=08761\ 0000434,name=Desk,product=FRITZ!DECT\ 200,tags=a\,b ",
+                "voltage=230,power=12.5,energy=4711 2000000000\n",
+            )
+        );
+    }
+
+    #[test]
+    fn records_chosen_temperature() {
+        let sensor = to_lines(Duration::from_secs(2), &[device("1")], Recorded::Sensor);
+        assert!(sensor.contains(" sensor=22,offset=-0.5 "));
+        let reported = to_lines(Duration::from_secs(2), &[device("1")], Recorded::Reported);
+        assert!(reported.contains(" temperature=21.5,offset=-0.5 "));
+    }
+}
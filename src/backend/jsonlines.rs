@@ -0,0 +1,167 @@
+use super::rotation::compress;
+use super::Backend;
+use crate::device::{Device, Output, Recorded};
+use crate::errors::*;
+use crate::inventory::Event;
+use crate::settings;
+
+use chrono::{DateTime, NaiveDate};
+use config::Value;
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    out_dir: String,
+    prefix: String,
+    compress: bool,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, JsonLines> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("out_dir".into(), ".".into()),
+            ("prefix".into(), "devices".into()),
+            ("compress".into(), false.into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+pub struct JsonLines {
+    settings: Settings,
+    current: Option<OutFile>,
+    recorded: Recorded,
+}
+
+struct OutFile {
+    date: NaiveDate,
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    device: Output<'a>,
+}
+
+// Tells events apart from device snapshots in the same file.
+#[derive(Serialize)]
+struct EventRecord<'a> {
+    timestamp: u64,
+    event: &'a Event,
+}
+
+impl<'de> Backend<'de> for JsonLines {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "JsonLines"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let ret = Self {
+            settings,
+            current: None,
+            recorded,
+        };
+        Ok(ret)
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        let timestamp = when.as_secs();
+        let secs = i64::try_from(timestamp).chain_err(|| "Timestamp out of range")?;
+        let day = DateTime::from_timestamp(secs, 0)
+            .chain_err(|| "Timestamp out of range")?
+            .date_naive();
+        let recorded = self.recorded;
+        let writer = self.writer(day)?;
+
+        for device in data {
+            serde_json::to_writer(
+                &mut *writer,
+                &Record {
+                    timestamp,
+                    device: Output::new(device, recorded),
+                },
+            )
+            .chain_err(|| "Error serializing json record")?;
+            writer
+                .write_all(b"\n")
+                .chain_err(|| "Error writing json record")?;
+        }
+        writer
+            .flush()
+            .chain_err(|| "Cannot flush out json records")?;
+
+        Ok(())
+    }
+
+    fn event(&mut self, when: Duration, events: &[Event]) -> Result<()> {
+        let timestamp = when.as_secs();
+        let secs = i64::try_from(timestamp).chain_err(|| "Timestamp out of range")?;
+        let day = DateTime::from_timestamp(secs, 0)
+            .chain_err(|| "Timestamp out of range")?
+            .date_naive();
+        let writer = self.writer(day)?;
+
+        for event in events {
+            serde_json::to_writer(&mut *writer, &EventRecord { timestamp, event })
+                .chain_err(|| "Error serializing json record")?;
+            writer
+                .write_all(b"\n")
+                .chain_err(|| "Error writing json record")?;
+        }
+        writer.flush().chain_err(|| "Cannot flush out json records")
+    }
+}
+
+impl JsonLines {
+    fn writer(&mut self, date: NaiveDate) -> Result<&mut BufWriter<File>> {
+        if self.current.as_ref().map(|c| c.date) != Some(date) {
+            self.rotate(date)?;
+        }
+        Ok(&mut self
+            .current
+            .as_mut()
+            .expect("Outfile was just opened.")
+            .writer)
+    }
+
+    fn rotate(&mut self, date: NaiveDate) -> Result<()> {
+        let path = Path::new(&self.settings.out_dir).join(format!(
+            "{}-{}.jsonl",
+            self.settings.prefix,
+            date.format("%Y-%m-%d")
+        ));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .chain_err(|| format!("Cannot open outfile {}", path.display()))?;
+        let new = OutFile {
+            date,
+            path,
+            writer: BufWriter::new(file),
+        };
+
+        let Some(old) = self.current.replace(new) else {
+            return Ok(());
+        };
+        let path = old.path;
+        drop(old.writer);
+        if self.settings.compress {
+            compress(&path).chain_err(|| format!("Cannot compress {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
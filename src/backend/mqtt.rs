@@ -0,0 +1,270 @@
+use super::backoff::Backoff;
+use super::template::render;
+use super::Backend;
+use crate::device::{Device, Recorded};
+use crate::errors::*;
+use crate::{print_errors, settings};
+
+use client::{Client, Message, Options, QoS};
+use config::Value;
+use discovery::Discovery;
+use error_chain::bail;
+use serde::{Deserialize, Serialize};
+
+use std::time::Duration;
+
+mod client;
+mod discovery;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    host: String,
+    port: u16,
+    tls: bool,
+    ca_file: String,
+    client_id: String,
+    username: String,
+    password: String,
+    topic: String,
+    qos: u8,
+    retain: bool,
+    availability_topic: String,
+    discovery: bool,
+    discovery_prefix: String,
+    keep_alive: u16,
+    timeout: u64,
+    min_backoff: u64,
+    max_backoff: u64,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Mqtt> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("host".into(), "localhost".into()),
+            ("port".into(), 1883.into()),
+            ("tls".into(), false.into()),
+            ("ca_file".into(), "".into()),
+            ("client_id".into(), "fritzlogger".into()),
+            ("username".into(), "".into()),
+            ("password".into(), "".into()),
+            ("topic".into(), "fritz/{ain}/{measurement}".into()),
+            ("qos".into(), 0.into()),
+            ("retain".into(), false.into()),
+            ("availability_topic".into(), "fritzlogger/status".into()),
+            ("discovery".into(), false.into()),
+            ("discovery_prefix".into(), "homeassistant".into()),
+            ("keep_alive".into(), 300.into()),
+            ("timeout".into(), 10.into()),
+            ("min_backoff".into(), 1.into()),
+            ("max_backoff".into(), 300.into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+pub struct Mqtt {
+    options: Options,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    availability_topic: String,
+    discovery: Option<Discovery>,
+    client: Option<Client>,
+    backoff: Backoff,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Mqtt {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Mqtt"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let qos = QoS::from_level(settings.qos)?;
+        // MQTT 3.1.1 section 3.1.2.9
+        if !settings.password.is_empty() && settings.username.is_empty() {
+            bail!("A password requires a username");
+        }
+        let will = if settings.availability_topic.is_empty() {
+            None
+        } else {
+            Some(Message {
+                topic: settings.availability_topic.clone(),
+                payload: b"offline".to_vec(),
+                qos,
+                retain: true,
+            })
+        };
+
+        let mut ret = Self {
+            options: Options {
+                host: settings.host,
+                port: settings.port,
+                tls: settings.tls,
+                ca_file: settings.ca_file,
+                client_id: settings.client_id,
+                username: settings.username,
+                password: settings.password,
+                keep_alive: settings.keep_alive,
+                timeout: Duration::from_secs(settings.timeout),
+                will,
+            },
+            topic: settings.topic,
+            qos,
+            retain: settings.retain,
+            availability_topic: settings.availability_topic,
+            discovery: if settings.discovery {
+                Some(Discovery::new(settings.discovery_prefix, qos))
+            } else {
+                None
+            },
+            client: None,
+            backoff: Backoff::new(settings.min_backoff, settings.max_backoff),
+            recorded,
+        };
+        if let Err(e) = ret.connect() {
+            ret.backoff.failed();
+            print_errors(Error::with_chain(
+                e,
+                "Backend Mqtt: Broker not ready. Retrying on next poll",
+            ));
+        }
+        Ok(ret)
+    }
+
+    /// Discovery removes the entities of devices missing from a poll.
+    fn needs_snapshots(&self) -> bool {
+        self.discovery.is_some()
+    }
+
+    fn log(&mut self, _: Duration, data: &[Device]) -> Result<()> {
+        // Connect first as that decides which discovery messages are due.
+        self.ensure_connected()?;
+        let (mut messages, announced) = match &self.discovery {
+            Some(discovery) => {
+                let topic = &self.topic;
+                let (messages, announced) = discovery.changes(
+                    data,
+                    self.recorded,
+                    &self.availability_topic,
+                    |device, measurement| expand_topic(topic, device, measurement),
+                );
+                (messages, Some(announced))
+            }
+            None => (Vec::new(), None),
+        };
+
+        let readings = data
+            .iter()
+            .flat_map(|device| {
+                device
+                    .readings(self.recorded)
+                    .into_iter()
+                    .map(move |(measurement, value)| (device, measurement, value))
+            })
+            .map(|(device, measurement, value)| Message {
+                topic: expand_topic(&self.topic, device, measurement),
+                payload: value.to_string().into_bytes(),
+                qos: self.qos,
+                retain: self.retain,
+            });
+        messages.extend(readings);
+        self.publish_all(&messages)?;
+
+        if let (Some(discovery), Some(announced)) = (&mut self.discovery, announced) {
+            discovery.commit(announced);
+        }
+        Ok(())
+    }
+}
+
+impl Mqtt {
+    /// The broker may have lost its retained messages. Hence everything is
+    /// announced again on every new connection.
+    fn connect(&mut self) -> Result<()> {
+        if let Some(discovery) = &mut self.discovery {
+            discovery.reset();
+        }
+        let mut client = Client::connect(&self.options)?;
+        if !self.availability_topic.is_empty() {
+            client.publish(&Message {
+                topic: self.availability_topic.clone(),
+                payload: b"online".to_vec(),
+                qos: self.qos,
+                retain: true,
+            })?;
+        }
+        self.client = Some(client);
+        Ok(())
+    }
+
+    fn ensure_connected(&mut self) -> Result<()> {
+        if self.client.is_some() {
+            return Ok(());
+        }
+        if let Some(remaining) = self.backoff.remaining() {
+            bail!("Reconnecting to broker in {:?}. Dropped sample", remaining);
+        }
+        if let Err(e) = self.connect() {
+            self.backoff.failed();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn publish_all(&mut self, messages: &[Message]) -> Result<()> {
+        let client = self.client.as_mut().expect("Client is connected.");
+        for msg in messages {
+            if let Err(e) = client.publish(msg) {
+                self.client = None;
+                self.backoff.failed();
+                return Err(e);
+            }
+        }
+        self.backoff.succeeded();
+        Ok(())
+    }
+}
+
+fn expand_topic(template: &str, device: &Device, measurement: &str) -> String {
+    let common = &device.common;
+    render(template, |key| match key {
+        "ain" => Some(topic_level(&common.unique_id)),
+        "name" => Some(topic_level(&common.name)),
+        "alias" => Some(topic_level(common.label())),
+        "room" => Some(topic_level(&common.room)),
+        "measurement" => Some(measurement.to_owned()),
+        _ => None,
+    })
+}
+
+/// Makes sure a value cannot add levels or wildcards to a topic.
+fn topic_level(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '+' | '#' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::tests::device;
+
+    #[test]
+    fn expands_topics_once() {
+        let mut desk = device("08761 0000434");
+        desk.common.name = "{ain}/+#".to_owned();
+        assert_eq!(
+            expand_topic("fritz/{ain}/{name}/{measurement}/{x}", &desk, "power"),
+            "fritz/08761 0000434/{ain}___/power/{x}"
+        );
+    }
+}
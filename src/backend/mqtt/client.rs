@@ -0,0 +1,376 @@
+use crate::errors::*;
+
+use error_chain::bail;
+use native_tls::{Certificate, TlsConnector};
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+}
+
+impl QoS {
+    pub fn from_level(level: u8) -> Result<Self> {
+        match level {
+            0 => Ok(QoS::AtMostOnce),
+            1 => Ok(QoS::AtLeastOnce),
+            _ => bail!("QoS {} is not supported. Use 0 or 1", level),
+        }
+    }
+}
+
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+pub struct Options {
+    pub host: String,
+    pub port: u16,
+    pub tls: bool,
+    pub ca_file: String,
+    pub client_id: String,
+    pub username: String,
+    pub password: String,
+    pub keep_alive: u16,
+    pub timeout: Duration,
+    pub will: Option<Message>,
+}
+
+trait Stream: Read + Write + Send {}
+impl<T: Read + Write + Send> Stream for T {}
+
+struct Connection {
+    stream: Box<dyn Stream>,
+    last_sent: Instant,
+}
+
+/// Just enough of MQTT 3.1.1 to publish messages. A thread pings the broker
+/// while nothing is published so that it keeps the connection.
+pub struct Client {
+    connection: Arc<Mutex<Connection>>,
+    next_packet_id: u16,
+}
+
+impl Client {
+    pub fn connect(options: &Options) -> Result<Self> {
+        let tcp = TcpStream::connect((options.host.as_str(), options.port))
+            .chain_err(|| format!("Cannot connect to {}:{}", options.host, options.port))?;
+        tcp.set_read_timeout(Some(options.timeout))
+            .and_then(|()| tcp.set_write_timeout(Some(options.timeout)))
+            .chain_err(|| "Cannot set socket timeouts")?;
+
+        let stream: Box<dyn Stream> = if options.tls {
+            let mut builder = TlsConnector::builder();
+            if !options.ca_file.is_empty() {
+                let pem = fs::read(&options.ca_file)
+                    .chain_err(|| format!("Cannot read {}", options.ca_file))?;
+                let cert = Certificate::from_pem(&pem).chain_err(|| "Invalid CA certificate")?;
+                builder.add_root_certificate(cert);
+            }
+            let connector = builder.build().chain_err(|| "Cannot set up TLS")?;
+            Box::new(
+                connector
+                    .connect(&options.host, tcp)
+                    .map_err(|e| Error::from(format!("TLS handshake failed: {}", e)))?,
+            )
+        } else {
+            Box::new(tcp)
+        };
+
+        let mut connection = Connection {
+            stream,
+            last_sent: Instant::now(),
+        };
+        connection.send(CONNECT, &connect_body(options)?)?;
+
+        let (kind, body) = connection.receive()?;
+        if kind & 0xf0 != CONNACK || body.len() != 2 {
+            bail!("Broker did not acknowledge the connection");
+        }
+        match body[1] {
+            0 => (),
+            4 | 5 => {
+                bail!("Broker refused the connection (bad username, password or not authorized)")
+            }
+            code => bail!("Broker refused the connection with code {}", code),
+        }
+
+        let connection = Arc::new(Mutex::new(connection));
+        if options.keep_alive > 0 {
+            let interval = Duration::from_secs(u64::from(options.keep_alive)) / 2;
+            let weak = Arc::downgrade(&connection);
+            thread::Builder::new()
+                .name("mqtt-ping".into())
+                .spawn(move || ping(&weak, interval))
+                .chain_err(|| "Cannot start pinging the broker")?;
+        }
+        Ok(Self {
+            connection,
+            next_packet_id: 1,
+        })
+    }
+
+    pub fn publish(&mut self, msg: &Message) -> Result<()> {
+        let mut body = Vec::with_capacity(msg.topic.len() + msg.payload.len() + 4);
+        put_str(&mut body, &msg.topic)?;
+
+        let packet_id = self.next_packet_id;
+        if msg.qos == QoS::AtLeastOnce {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+            self.next_packet_id = packet_id.checked_add(1).unwrap_or(1);
+        }
+        body.extend_from_slice(&msg.payload);
+
+        let flags = (msg.qos as u8) << 1 | u8::from(msg.retain);
+        let mut connection = self.connection.lock().unwrap();
+        connection.send(PUBLISH | flags, &body)?;
+
+        if msg.qos == QoS::AtMostOnce {
+            return Ok(());
+        }
+        loop {
+            let (kind, body) = connection.receive()?;
+            if kind & 0xf0 == PUBACK && body[..] == packet_id.to_be_bytes() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn send(&mut self, header: u8, body: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(body.len() + 5);
+        packet.push(header);
+        put_length(&mut packet, body.len())?;
+        packet.extend_from_slice(body);
+        self.stream
+            .write_all(&packet)
+            .and_then(|()| self.stream.flush())
+            .chain_err(|| "Cannot send packet to broker")?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        self.send(PINGREQ, &[])?;
+        loop {
+            let (kind, _) = self.receive()?;
+            if kind & 0xf0 == PINGRESP {
+                return Ok(());
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<(u8, Vec<u8>)> {
+        let mut byte = [0; 1];
+        self.stream
+            .read_exact(&mut byte)
+            .chain_err(|| "Cannot receive packet from broker")?;
+        let kind = byte[0];
+
+        let mut len = 0;
+        for shift in (0..28).step_by(7) {
+            self.stream
+                .read_exact(&mut byte)
+                .chain_err(|| "Cannot receive packet from broker")?;
+            len |= usize::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec![0; len];
+        self.stream
+            .read_exact(&mut body)
+            .chain_err(|| "Cannot receive packet from broker")?;
+        Ok((kind, body))
+    }
+}
+
+/// Runs until the client is dropped or pinging fails. A broken connection
+/// is noticed by the next publish which then reconnects.
+fn ping(connection: &Weak<Mutex<Connection>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let Some(connection) = connection.upgrade() else {
+            return;
+        };
+        let mut connection = connection.lock().unwrap();
+        if connection.last_sent.elapsed() >= interval && connection.ping().is_err() {
+            return;
+        }
+    }
+}
+
+fn connect_body(options: &Options) -> Result<Vec<u8>> {
+    let mut flags = 0x02; // clean session
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT")?;
+    body.push(4); // protocol level 3.1.1
+
+    if let Some(will) = &options.will {
+        flags |= 0x04 | (will.qos as u8) << 3;
+        if will.retain {
+            flags |= 0x20;
+        }
+    }
+    if !options.username.is_empty() {
+        flags |= 0x80;
+    }
+    if !options.password.is_empty() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&options.keep_alive.to_be_bytes());
+
+    put_str(&mut body, &options.client_id)?;
+    if let Some(will) = &options.will {
+        put_str(&mut body, &will.topic)?;
+        put_bytes(&mut body, &will.payload)?;
+    }
+    if !options.username.is_empty() {
+        put_str(&mut body, &options.username)?;
+    }
+    if !options.password.is_empty() {
+        put_str(&mut body, &options.password)?;
+    }
+    Ok(body)
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) -> Result<()> {
+    put_bytes(buf, s.as_bytes())
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    let len = u16::try_from(bytes.len()).chain_err(|| "String too long for MQTT")?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+fn put_length(buf: &mut Vec<u8>, mut len: usize) -> Result<()> {
+    if len > 268_435_455 {
+        bail!("Packet too large for MQTT");
+    }
+    loop {
+        #[allow(clippy::cast_possible_truncation)]
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if len == 0 {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    fn options() -> Options {
+        Options {
+            host: "localhost".to_owned(),
+            port: 1883,
+            tls: false,
+            ca_file: String::new(),
+            client_id: "fl".to_owned(),
+            username: String::new(),
+            password: String::new(),
+            keep_alive: 300,
+            timeout: Duration::from_secs(10),
+            will: None,
+        }
+    }
+
+    #[test]
+    fn pings_while_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut options = options();
+        options.port = listener.local_addr().unwrap().port();
+        options.keep_alive = 1;
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connect = [0; 16];
+            stream.read_exact(&mut connect).unwrap();
+            assert_eq!(connect[0], CONNECT);
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            let mut ping = [0; 2];
+            stream.read_exact(&mut ping).unwrap();
+            stream.write_all(&[PINGRESP, 0]).unwrap();
+            ping
+        });
+
+        let client = Client::connect(&options).unwrap();
+        assert_eq!(broker.join().unwrap(), [PINGREQ, 0]);
+        drop(client);
+    }
+
+    fn length(len: usize) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_length(&mut buf, len).unwrap();
+        buf
+    }
+
+    #[test]
+    fn remaining_length() {
+        assert_eq!(length(0), [0x00]);
+        assert_eq!(length(127), [0x7f]);
+        assert_eq!(length(128), [0x80, 0x01]);
+        assert_eq!(length(16_383), [0xff, 0x7f]);
+        assert_eq!(length(16_384), [0x80, 0x80, 0x01]);
+        assert_eq!(length(268_435_455), [0xff, 0xff, 0xff, 0x7f]);
+        assert!(put_length(&mut Vec::new(), 268_435_456).is_err());
+    }
+
+    #[test]
+    fn connect_without_credentials() {
+        let body = connect_body(&options()).unwrap();
+        assert_eq!(
+            body,
+            [0, 4, b'M', b'Q', b'T', b'T', 4, 0x02, 0x01, 0x2c, 0, 2, b'f', b'l']
+        );
+    }
+
+    #[test]
+    fn connect_with_will_and_credentials() {
+        let mut options = options();
+        options.username = "u".to_owned();
+        options.password = "p".to_owned();
+        options.will = Some(Message {
+            topic: "t".to_owned(),
+            payload: b"off".to_vec(),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        });
+        let body = connect_body(&options).unwrap();
+        // username, password, will retain, will QoS 1, will and clean session
+        assert_eq!(body[7], 0x80 | 0x40 | 0x20 | 0x08 | 0x04 | 0x02);
+        assert_eq!(
+            &body[10..],
+            [0, 2, b'f', b'l', 0, 1, b't', 0, 3, b'o', b'f', b'f', 0, 1, b'u', 0, 1, b'p']
+        );
+    }
+}
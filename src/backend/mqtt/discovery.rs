@@ -0,0 +1,237 @@
+use super::client::{Message, QoS};
+use crate::device::{self, Device, Recorded};
+
+use serde_json::json;
+
+use std::collections::HashMap;
+
+/// How a measurement is presented to Home Assistant.
+struct Kind {
+    component: &'static str,
+    device_class: &'static str,
+    unit: Option<&'static str>,
+    state_class: Option<&'static str>,
+    value_template: Option<&'static str>,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Announcement {
+    name: String,
+    room: String,
+    manufacturer: String,
+    productname: String,
+    fwversion: String,
+    measurements: Vec<&'static str>,
+}
+
+pub type Announced = HashMap<String, Announcement>;
+
+/// Keeps Home Assistant's entities in sync with the devices of the box.
+pub struct Discovery {
+    prefix: String,
+    qos: QoS,
+    announced: Announced,
+}
+
+impl Discovery {
+    pub fn new(prefix: String, qos: QoS) -> Self {
+        Self {
+            prefix,
+            qos,
+            announced: HashMap::new(),
+        }
+    }
+
+    /// Messages that are needed to bring Home Assistant up to date. The new
+    /// state must be `commit`ed once they were published.
+    pub fn changes<F>(
+        &self,
+        data: &[Device],
+        recorded: Recorded,
+        availability_topic: &str,
+        state_topic: F,
+    ) -> (Vec<Message>, Announced)
+    where
+        F: Fn(&Device, &str) -> String,
+    {
+        let mut messages = Vec::new();
+        let mut current = HashMap::with_capacity(data.len());
+
+        for device in data {
+            let common = &device.common;
+            let announcement = Announcement {
+                name: common.label().to_owned(),
+                room: common.room.clone(),
+                manufacturer: common.manufacturer.clone(),
+                productname: common.productname.clone(),
+                fwversion: common.fwversion.clone(),
+                measurements: device
+                    .readings(recorded)
+                    .into_iter()
+                    .map(|(m, _)| m)
+                    .filter(|m| kind(m).is_some())
+                    .collect(),
+            };
+
+            if self.announced.get(&common.unique_id) != Some(&announcement) {
+                if let Some(old) = self.announced.get(&common.unique_id) {
+                    for m in &old.measurements {
+                        if !announcement.measurements.contains(m) {
+                            messages.push(self.remove(&common.unique_id, m));
+                        }
+                    }
+                }
+                for m in &announcement.measurements {
+                    let state_topic = state_topic(device, m);
+                    messages.push(self.announce(
+                        &common.unique_id,
+                        &announcement,
+                        m,
+                        &state_topic,
+                        availability_topic,
+                    ));
+                }
+            }
+            current.insert(common.unique_id.clone(), announcement);
+        }
+
+        for (ain, old) in &self.announced {
+            if current.contains_key(ain) {
+                continue;
+            }
+            for m in &old.measurements {
+                messages.push(self.remove(ain, m));
+            }
+        }
+
+        (messages, current)
+    }
+
+    pub fn commit(&mut self, announced: Announced) {
+        self.announced = announced;
+    }
+
+    /// Forgets what was announced so that the next changes announce it all.
+    pub fn reset(&mut self) {
+        self.announced.clear();
+    }
+
+    fn announce(
+        &self,
+        ain: &str,
+        device: &Announcement,
+        measurement: &str,
+        state_topic: &str,
+        availability_topic: &str,
+    ) -> Message {
+        let kind = kind(measurement).expect("Only known measurements are announced.");
+        let id = object_id(ain);
+        let mut config = json!({
+            "name": format!("{} {}", device.name, measurement),
+            "unique_id": format!("fritz_{}_{}", id, measurement),
+            "state_topic": state_topic,
+            "device_class": kind.device_class,
+            "device": {
+                "identifiers": [format!("fritz_{}", id)],
+                "name": device.name,
+                "manufacturer": device.manufacturer,
+                "model": device.productname,
+                "sw_version": device.fwversion,
+            },
+        });
+        let map = config.as_object_mut().expect("Config is an object.");
+        if !device.room.is_empty() {
+            map["device"]["suggested_area"] = device.room.clone().into();
+        }
+        if let Some(unit) = kind.unit {
+            map.insert("unit_of_measurement".into(), unit.into());
+        }
+        if let Some(state_class) = kind.state_class {
+            map.insert("state_class".into(), state_class.into());
+        }
+        // Only raw readings need to be scaled by Home Assistant.
+        if let Some(template) = kind.value_template.filter(|_| device::raw_units()) {
+            map.insert("value_template".into(), template.into());
+        }
+        if kind.component == "binary_sensor" {
+            map.insert("payload_on".into(), "1".into());
+            map.insert("payload_off".into(), "0".into());
+        }
+        if !availability_topic.is_empty() {
+            map.insert("availability_topic".into(), availability_topic.into());
+        }
+
+        Message {
+            topic: self.config_topic(kind.component, &id, measurement),
+            payload: config.to_string().into_bytes(),
+            qos: self.qos,
+            retain: true,
+        }
+    }
+
+    fn remove(&self, ain: &str, measurement: &str) -> Message {
+        let kind = kind(measurement).expect("Only known measurements are announced.");
+        Message {
+            topic: self.config_topic(kind.component, &object_id(ain), measurement),
+            payload: Vec::new(),
+            qos: self.qos,
+            retain: true,
+        }
+    }
+
+    fn config_topic(&self, component: &str, id: &str, measurement: &str) -> String {
+        format!(
+            "{}/{}/{}_{}/config",
+            self.prefix, component, id, measurement
+        )
+    }
+}
+
+fn kind(measurement: &str) -> Option<Kind> {
+    let kind = match measurement {
+        "present" => Kind {
+            component: "binary_sensor",
+            device_class: "connectivity",
+            unit: None,
+            state_class: None,
+            value_template: None,
+        },
+        "temperature" | "sensor_temperature" => Kind {
+            component: "sensor",
+            device_class: "temperature",
+            unit: Some("°C"),
+            state_class: Some("measurement"),
+            value_template: Some("{{ value | float / 10 }}"),
+        },
+        "power" => Kind {
+            component: "sensor",
+            device_class: "power",
+            unit: Some("W"),
+            state_class: Some("measurement"),
+            value_template: Some("{{ value | float / 1000 }}"),
+        },
+        "voltage" => Kind {
+            component: "sensor",
+            device_class: "voltage",
+            unit: Some("V"),
+            state_class: Some("measurement"),
+            value_template: Some("{{ value | float / 1000 }}"),
+        },
+        "energy" => Kind {
+            component: "sensor",
+            device_class: "energy",
+            unit: Some("Wh"),
+            state_class: Some("total_increasing"),
+            value_template: None,
+        },
+        _ => return None,
+    };
+    Some(kind)
+}
+
+/// Home Assistant only accepts `[a-zA-Z0-9_-]` as object id.
+fn object_id(ain: &str) -> String {
+    ain.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,392 @@
+use super::Backend;
+use crate::device::{self, Device, Recorded, READINGS};
+use crate::errors::*;
+use crate::settings;
+
+use chrono::{DateTime, NaiveDate};
+use config::Value;
+use error_chain::bail;
+use parquet::basic::Compression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, DataType, DoubleType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    out_dir: String,
+    prefix: String,
+    rotate: String,
+    max_rows: usize,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Parquet> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("out_dir".into(), ".".into()),
+            ("prefix".into(), "readings".into()),
+            ("rotate".into(), "Daily".into()),
+            ("max_rows".into(), 100_000.into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+#[derive(Default)]
+struct Rows {
+    timestamps: Vec<i64>,
+    ains: Vec<ByteArray>,
+    names: Vec<ByteArray>,
+    values: Vec<f64>,
+}
+
+impl Rows {
+    fn push(&mut self, row: &Row) {
+        self.timestamps.push(row.timestamp);
+        self.ains.push(row.ain.as_str().into());
+        self.names.push(row.name.as_str().into());
+        self.values.push(row.value);
+    }
+}
+
+/// A line of the buffer file that keeps rows across restarts until they
+/// made it into a parquet file.
+#[derive(Deserialize, Serialize)]
+struct Row {
+    timestamp: i64,
+    ain: String,
+    name: String,
+    value: f64,
+}
+
+pub struct Parquet {
+    settings: Settings,
+    daily: bool,
+    day: Option<NaiveDate>,
+    // buffered rows per measurement
+    rows: HashMap<&'static str, Rows>,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Parquet {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Parquet"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let daily = match settings.rotate.as_str() {
+            "Daily" => true,
+            "Rows" => false,
+            _ => bail!(
+                "Rotate \"{}\" does not exist. Use Daily or Rows",
+                settings.rotate
+            ),
+        };
+        if settings.max_rows == 0 {
+            bail!("Max rows must be positive");
+        }
+        fs::create_dir_all(&settings.out_dir)
+            .chain_err(|| format!("Cannot create directory {}", settings.out_dir))?;
+
+        let mut ret = Self {
+            settings,
+            daily,
+            day: None,
+            rows: HashMap::new(),
+            recorded,
+        };
+        ret.restore()?;
+        Ok(ret)
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        let secs = i64::try_from(when.as_secs()).chain_err(|| "Timestamp out of range")?;
+        let day = DateTime::from_timestamp(secs, 0)
+            .chain_err(|| "Timestamp out of range")?
+            .date_naive();
+        let mut rotated = Ok(());
+        if self.daily && self.day.is_some() && self.day != Some(day) {
+            rotated = self.flush_all();
+        }
+        // A failed rotation is retried on the next poll. The rows of this
+        // poll end up in the file of the previous day then, which beats
+        // dropping them.
+        if rotated.is_ok() {
+            self.day = Some(day);
+        }
+
+        let mut lines: HashMap<&'static str, Vec<u8>> = HashMap::new();
+        for device in data {
+            for (measurement, value) in device.readings(self.recorded) {
+                let row = Row {
+                    timestamp: secs * 1000,
+                    ain: device.common.unique_id.clone(),
+                    name: device.common.name.clone(),
+                    value,
+                };
+                let buffer = lines.entry(measurement).or_default();
+                serde_json::to_writer(&mut *buffer, &row).chain_err(|| "Cannot serialize row")?;
+                buffer.push(b'\n');
+                self.rows.entry(measurement).or_default().push(&row);
+            }
+        }
+        for (measurement, buffer) in lines {
+            let path = self.buffer_path(measurement);
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(&buffer))
+                .chain_err(|| format!("Cannot write buffer {}", path.display()))?;
+        }
+        rotated?;
+
+        // Daily files are capped as well so that memory stays bounded.
+        let full: Vec<_> = self
+            .rows
+            .iter()
+            .filter(|(_, rows)| rows.timestamps.len() >= self.settings.max_rows)
+            .map(|(measurement, _)| *measurement)
+            .collect();
+        for measurement in full {
+            self.flush(measurement)?;
+        }
+        Ok(())
+    }
+}
+
+impl Parquet {
+    /// Picks up the rows buffered by the last run. Lines that were torn by
+    /// a crash are skipped.
+    fn restore(&mut self) -> Result<()> {
+        for measurement in READINGS {
+            let path = self.buffer_path(measurement);
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    return Err(Error::with_chain(
+                        e,
+                        format!("Cannot open buffer {}", path.display()),
+                    ))
+                }
+            };
+            let rows = self.rows.entry(measurement).or_default();
+            for line in BufReader::new(file).split(b'\n') {
+                let line = line.chain_err(|| format!("Cannot read buffer {}", path.display()))?;
+                if let Ok(row) = serde_json::from_slice::<Row>(&line) {
+                    rows.push(&row);
+                }
+            }
+        }
+
+        // rows of an earlier day must not end up in today's file
+        let first = self
+            .rows
+            .values()
+            .filter_map(|rows| rows.timestamps.first())
+            .min()
+            .copied();
+        if let Some(first) = first {
+            let day = DateTime::from_timestamp(first / 1000, 0)
+                .chain_err(|| "Timestamp out of range")?
+                .date_naive();
+            self.day = Some(day);
+        }
+        Ok(())
+    }
+
+    fn buffer_path(&self, measurement: &str) -> PathBuf {
+        Path::new(&self.settings.out_dir)
+            .join(format!(".{}-{}.buffer", self.settings.prefix, measurement))
+    }
+
+    fn flush_all(&mut self) -> Result<()> {
+        let measurements: Vec<_> = self.rows.keys().copied().collect();
+        for measurement in measurements {
+            self.flush(measurement)?;
+        }
+        Ok(())
+    }
+
+    /// The rows stay buffered until they are in place so that a failed
+    /// write can be retried.
+    fn flush(&mut self, measurement: &'static str) -> Result<()> {
+        let rows = match self.rows.get(measurement) {
+            Some(rows) if !rows.timestamps.is_empty() => rows,
+            _ => {
+                self.rows.remove(measurement);
+                return Ok(());
+            }
+        };
+        let path = self.path(measurement, rows.timestamps[0] / 1000)?;
+
+        // Readers must never see a half written file. Hence we write to a
+        // hidden file first and move it into place once it is complete.
+        let file_name = path
+            .file_name()
+            .expect("Path was built from a file name.")
+            .to_string_lossy();
+        let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+        write_file(&tmp_path, measurement, rows)
+            .chain_err(|| format!("Cannot write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .chain_err(|| format!("Cannot move {} into place", path.display()))?;
+        self.rows.remove(measurement);
+        // Should we crash right here the rows are written a second time.
+        // That beats losing them.
+        let buffer = self.buffer_path(measurement);
+        match fs::remove_file(&buffer) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result.chain_err(|| format!("Cannot remove buffer {}", buffer.display())),
+        }
+    }
+
+    /// Never overwrites an existing file as we might have been restarted
+    /// in the middle of a day.
+    fn path(&self, measurement: &str, first: i64) -> Result<PathBuf> {
+        let time = DateTime::from_timestamp(first, 0).chain_err(|| "Timestamp out of range")?;
+        let stem = if self.daily {
+            format!(
+                "{}-{}-{}",
+                self.settings.prefix,
+                measurement,
+                time.format("%Y-%m-%d")
+            )
+        } else {
+            format!(
+                "{}-{}-{}",
+                self.settings.prefix,
+                measurement,
+                time.format("%Y%m%dT%H%M%S")
+            )
+        };
+
+        let dir = Path::new(&self.settings.out_dir);
+        let mut path = dir.join(format!("{}.parquet", stem));
+        let mut n = 1;
+        while path.exists() {
+            path = dir.join(format!("{}.{}.parquet", stem, n));
+            n += 1;
+        }
+        Ok(path)
+    }
+}
+
+fn write_file(path: &Path, measurement: &str, rows: &Rows) -> Result<()> {
+    let value_type = if measurement == "present" {
+        "BOOLEAN"
+    } else if device::raw_units() {
+        "INT64"
+    } else {
+        "DOUBLE"
+    };
+    let schema = parse_message_type(&format!(
+        "message {} {{
+            REQUIRED INT64 timestamp (TIMESTAMP(MILLIS,true));
+            REQUIRED BYTE_ARRAY ain (UTF8);
+            REQUIRED BYTE_ARRAY name (UTF8);
+            REQUIRED {} value;
+        }}",
+        measurement, value_type
+    ))
+    .chain_err(|| "Invalid schema")?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+
+    let file = File::create(path).chain_err(|| "Cannot create file")?;
+    let mut writer = SerializedFileWriter::new(file, Arc::new(schema), Arc::new(properties))
+        .chain_err(|| "Cannot create writer")?;
+    let mut row_group = writer
+        .next_row_group()
+        .chain_err(|| "Cannot create row group")?;
+    write_column::<Int64Type>(&mut row_group, &rows.timestamps)?;
+    write_column::<ByteArrayType>(&mut row_group, &rows.ains)?;
+    write_column::<ByteArrayType>(&mut row_group, &rows.names)?;
+    if measurement == "present" {
+        let present: Vec<_> = rows.values.iter().map(|v| *v != 0.0).collect();
+        write_column::<BoolType>(&mut row_group, &present)?;
+    } else if device::raw_units() {
+        #[allow(clippy::cast_possible_truncation)]
+        let raw: Vec<_> = rows.values.iter().map(|v| *v as i64).collect();
+        write_column::<Int64Type>(&mut row_group, &raw)?;
+    } else {
+        write_column::<DoubleType>(&mut row_group, &rows.values)?;
+    }
+    row_group.close().chain_err(|| "Cannot close row group")?;
+
+    let file = writer.into_inner().chain_err(|| "Cannot close writer")?;
+    file.sync_all().chain_err(|| "Cannot sync file")
+}
+
+fn write_column<T: DataType>(
+    row_group: &mut SerializedRowGroupWriter<File>,
+    values: &[T::T],
+) -> Result<()> {
+    let mut column = row_group
+        .next_column()
+        .chain_err(|| "Cannot create column")?
+        .chain_err(|| "Schema has too few columns")?;
+    column
+        .typed::<T>()
+        .write_batch(values, None, None)
+        .chain_err(|| "Cannot write column")?;
+    column.close().chain_err(|| "Cannot close column")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::tests::device;
+
+    use std::process;
+
+    #[test]
+    fn keeps_rows_when_writing_fails() {
+        let out_dir = std::env::temp_dir().join(format!("fritzlogger-parquet-{}", process::id()));
+        let _ = fs::remove_dir_all(&out_dir);
+        let mut parquet = Parquet::new(Settings {
+            out_dir: out_dir.to_string_lossy().into_owned(),
+            prefix: "readings".to_owned(),
+            rotate: "Daily".to_owned(),
+            max_rows: 100,
+            temperature: "Both".to_owned(),
+        })
+        .unwrap();
+        let midnight = 86_400;
+        parquet
+            .log(Duration::from_secs(midnight - 60), &[device("1")])
+            .unwrap();
+
+        // the temporary file cannot be created where a directory is
+        let blocker = out_dir.join(".readings-power-1970-01-01.parquet.tmp");
+        fs::create_dir(&blocker).unwrap();
+        assert!(parquet
+            .log(Duration::from_secs(midnight), &[device("1")])
+            .is_err());
+        assert_eq!(parquet.rows["power"].timestamps, [86_340_000, 86_400_000]);
+        fs::remove_dir(&blocker).unwrap();
+
+        parquet
+            .log(Duration::from_secs(midnight + 60), &[device("1")])
+            .unwrap();
+        assert!(out_dir.join("readings-power-1970-01-01.parquet").exists());
+        assert_eq!(parquet.rows["power"].timestamps, [86_460_000]);
+
+        fs::remove_dir_all(&out_dir).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+// Stay below the common ethernet MTU so datagrams do not get fragmented.
+const MAX_DATAGRAM: usize = 1400;
+
+/// Replaces everything that might be a separator in a plaintext protocol.
+pub fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Splits the lines into chunks that fit into a single datagram.
+pub fn datagrams(lines: &str) -> impl Iterator<Item = &str> {
+    let mut rest = lines;
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+        let mut end = 0;
+        for (i, _) in rest.match_indices('\n') {
+            if i + 1 > MAX_DATAGRAM && end > 0 {
+                break;
+            }
+            end = i + 1;
+        }
+        if end == 0 {
+            end = rest.len();
+        }
+        let (chunk, tail) = rest.split_at(end);
+        rest = tail;
+        Some(chunk)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_small_batches_together() {
+        let chunks: Vec<_> = datagrams("a 1\nb 2\n").collect();
+        assert_eq!(chunks, ["a 1\nb 2\n"]);
+        assert_eq!(datagrams("").count(), 0);
+    }
+
+    #[test]
+    fn splits_at_lines() {
+        let line = "x".repeat(999) + "\n";
+        let lines = line.repeat(3);
+        let chunks: Vec<_> = datagrams(&lines).collect();
+        assert_eq!(chunks, [line.as_str(), line.as_str(), line.as_str()]);
+    }
+
+    #[test]
+    fn passes_on_overlong_lines() {
+        let line = "x".repeat(MAX_DATAGRAM * 2) + "\n";
+        let lines = line.clone() + "a 1";
+        let chunks: Vec<_> = datagrams(&lines).collect();
+        assert_eq!(chunks, [line.as_str(), "a 1"]);
+    }
+
+    #[test]
+    fn sanitizes_separators() {
+        assert_eq!(sanitize("Living room.1:a-b"), "Living_room_1_a-b");
+    }
+}
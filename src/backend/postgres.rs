@@ -0,0 +1,218 @@
+use super::Backend;
+use crate::device::{Device, Recorded};
+use crate::errors::*;
+use crate::{print_errors, settings};
+
+use chrono::DateTime;
+use config::Value;
+use error_chain::bail;
+use postgres::NoTls;
+use r2d2::Pool;
+use r2d2_postgres::PostgresConnectionManager;
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io::Write;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    url: String,
+    table: String,
+    pool_size: u32,
+    timeout: u64,
+    timescale: bool,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Postgres> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            (
+                "url".into(),
+                "postgresql://postgres@localhost/fritzlogger".into(),
+            ),
+            ("table".into(), "readings".into()),
+            ("pool_size".into(), 2.into()),
+            ("timeout".into(), 10.into()),
+            ("timescale".into(), false.into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+pub struct Postgres {
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    table: String,
+    timescale: bool,
+    // Tables are created lazily so that we can start while the database is down.
+    created: bool,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Postgres {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Postgres"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        if settings.table.is_empty()
+            || !settings
+                .table
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            bail!(
+                "Table \"{}\" must only contain letters, digits and underscores",
+                settings.table
+            );
+        }
+
+        let config = settings.url.parse().chain_err(|| "Invalid database url")?;
+        // Checked out connections are validated by the pool which gives us
+        // reconnects after a database restart for free.
+        let pool = Pool::builder()
+            .max_size(settings.pool_size)
+            .connection_timeout(Duration::from_secs(settings.timeout))
+            .build_unchecked(PostgresConnectionManager::new(config, NoTls));
+
+        let mut ret = Self {
+            pool,
+            table: settings.table,
+            timescale: settings.timescale,
+            created: false,
+            recorded,
+        };
+        if let Err(e) = ret.create_table() {
+            print_errors(Error::with_chain(
+                e,
+                "Backend Postgres: Database not ready. Retrying on next poll",
+            ));
+        }
+        Ok(ret)
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        if !self.created {
+            self.create_table()?;
+        }
+
+        let secs = i64::try_from(when.as_secs()).chain_err(|| "Timestamp out of range")?;
+        let timestamp = DateTime::from_timestamp(secs, 0)
+            .chain_err(|| "Timestamp out of range")?
+            .format("%Y-%m-%d %H:%M:%S+00");
+        let mut rows = String::new();
+        for device in data {
+            let ain = escape(&device.common.unique_id);
+            for (measurement, value) in device.readings(self.recorded) {
+                writeln!(rows, "{}\t{}\t{}\t{}", timestamp, ain, measurement, value)
+                    .expect("Writing to a String cannot fail.");
+            }
+        }
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let mut client = self.pool.get().chain_err(|| "Cannot connect to database")?;
+        let mut tx = client
+            .transaction()
+            .chain_err(|| "Cannot start transaction")?;
+        let upsert = tx
+            .prepare(&format!(
+                "INSERT INTO {}_devices (ain, name, alias, room, tags)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (ain) DO UPDATE SET
+                    name = excluded.name,
+                    alias = excluded.alias,
+                    room = excluded.room,
+                    tags = excluded.tags",
+                self.table
+            ))
+            .chain_err(|| "Cannot prepare device update")?;
+        for device in data {
+            let common = &device.common;
+            tx.execute(
+                &upsert,
+                &[
+                    &common.unique_id,
+                    &common.name,
+                    &common.alias,
+                    &common.room,
+                    &common.tags,
+                ],
+            )
+            .chain_err(|| format!("Cannot update device {}", common.unique_id))?;
+        }
+        let mut writer = tx
+            .copy_in(
+                format!(
+                    "COPY {} (timestamp, ain, measurement, value) FROM STDIN",
+                    self.table
+                )
+                .as_str(),
+            )
+            .chain_err(|| "Cannot start copy")?;
+        writer
+            .write_all(rows.as_bytes())
+            .chain_err(|| "Cannot copy rows")?;
+        writer.finish().chain_err(|| "Cannot finish copy")?;
+        tx.commit().chain_err(|| "Cannot commit transaction")
+    }
+}
+
+impl Postgres {
+    fn create_table(&mut self) -> Result<()> {
+        let mut client = self.pool.get().chain_err(|| "Cannot connect to database")?;
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (
+                    timestamp TIMESTAMPTZ NOT NULL,
+                    ain TEXT NOT NULL,
+                    measurement TEXT NOT NULL,
+                    value DOUBLE PRECISION NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS {table}_ain_measurement_timestamp_idx
+                    ON {table} (ain, measurement, timestamp DESC);
+                CREATE TABLE IF NOT EXISTS {table}_devices (
+                    ain TEXT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    alias TEXT NOT NULL,
+                    room TEXT NOT NULL,
+                    tags TEXT[] NOT NULL
+                );",
+                table = self.table
+            ))
+            .chain_err(|| format!("Cannot create table {}", self.table))?;
+
+        if self.timescale {
+            client
+                .batch_execute(&format!(
+                    "SELECT create_hypertable('{}', 'timestamp', if_not_exists => TRUE)",
+                    self.table
+                ))
+                .chain_err(|| "Cannot create hypertable. Is TimescaleDB installed?")?;
+        }
+
+        self.created = true;
+        Ok(())
+    }
+}
+
+/// Escapes a value for the text format of COPY.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
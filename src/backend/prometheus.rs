@@ -0,0 +1,274 @@
+use super::Backend;
+use crate::device::{Device, Recorded};
+use crate::errors::*;
+use crate::{print_errors, settings, stats};
+
+use config::Value;
+use serde::{Deserialize, Serialize};
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const MAX_REQUEST_SIZE: usize = 8192;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    listen: String,
+    path: String,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Prometheus> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("listen".into(), "0.0.0.0:9150".into()),
+            ("path".into(), "/metrics".into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+pub struct Prometheus {
+    // Device metrics are rendered on every poll so that a scrape never has
+    // to wait for the backend.
+    devices: Arc<Mutex<String>>,
+    recorded: Recorded,
+}
+
+struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    samples: String,
+}
+
+impl<'de> Backend<'de> for Prometheus {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Prometheus"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let listener = TcpListener::bind(&settings.listen)
+            .chain_err(|| format!("Cannot listen on {}", settings.listen))?;
+        let devices = Arc::new(Mutex::new(String::new()));
+
+        let server_devices = devices.clone();
+        thread::Builder::new()
+            .name("backend-Prometheus-http".into())
+            .spawn(move || serve(&listener, &settings.path, &server_devices))
+            .chain_err(|| "Cannot start http server")?;
+
+        Ok(Self { devices, recorded })
+    }
+
+    /// Devices missing from a poll would drop out of the metrics.
+    fn needs_snapshots(&self) -> bool {
+        true
+    }
+
+    fn log(&mut self, _: Duration, data: &[Device]) -> Result<()> {
+        let text = render_devices(data, self.recorded);
+        *self.devices.lock().unwrap() = text;
+        Ok(())
+    }
+}
+
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            samples: String::new(),
+        }
+    }
+
+    fn add(&mut self, labels: &str, value: f64) {
+        writeln!(self.samples, "{}{} {}", self.name, labels, value)
+            .expect("Writing to a String cannot fail.");
+    }
+
+    fn render(&self, out: &mut String) {
+        if self.samples.is_empty() {
+            return;
+        }
+        writeln!(out, "# HELP {} {}", self.name, self.help)
+            .and_then(|()| writeln!(out, "# TYPE {} {}", self.name, self.kind))
+            .expect("Writing to a String cannot fail.");
+        out.push_str(&self.samples);
+    }
+}
+
+fn render_devices(data: &[Device], recorded: Recorded) -> String {
+    let mut present = Family::new(
+        "fritz_device_present",
+        "gauge",
+        "Whether the device is connected to the box.",
+    );
+    let mut temperature = Family::new(
+        "fritz_temperature_celsius",
+        "gauge",
+        "Temperature measured by the device including its offset.",
+    );
+    let mut sensor = Family::new(
+        "fritz_sensor_temperature_celsius",
+        "gauge",
+        "Temperature measured by the device without its offset.",
+    );
+    let mut offset = Family::new(
+        "fritz_temperature_offset_celsius",
+        "gauge",
+        "Offset configured for the temperature sensor.",
+    );
+    let mut power = Family::new(
+        "fritz_power_watts",
+        "gauge",
+        "Power currently drawn through the device.",
+    );
+    let mut voltage = Family::new(
+        "fritz_voltage_volts",
+        "gauge",
+        "Voltage currently measured by the device.",
+    );
+    let mut energy = Family::new(
+        "fritz_energy_wh_total",
+        "counter",
+        "Energy drawn through the device since it was reset.",
+    );
+
+    for device in data {
+        let common = &device.common;
+        let labels = format!(
+            "{{ain=\"{}\",name=\"{}\",alias=\"{}\",room=\"{}\",tags=\"{}\"}}",
+            escape(&common.unique_id),
+            escape(&common.name),
+            escape(&common.alias),
+            escape(&common.room),
+            escape(&common.tags.join(","))
+        );
+
+        present.add(&labels, if device.common.present { 1.0 } else { 0.0 });
+
+        if let Some(t) = &device.temperature {
+            if recorded.reported() {
+                temperature.add(&labels, t.temperature.value());
+            }
+            if recorded.sensor() {
+                sensor.add(&labels, t.sensor.value());
+            }
+            offset.add(&labels, t.offset.value());
+        }
+
+        if let Some(p) = &device.powermeter {
+            power.add(&labels, p.power.value());
+            voltage.add(&labels, p.voltage.value());
+            energy.add(&labels, p.energy.value());
+        }
+    }
+
+    let mut out = String::new();
+    for family in &[present, temperature, sensor, offset, power, voltage, energy] {
+        family.render(&mut out);
+    }
+    out
+}
+
+fn render_internal() -> String {
+    let poll = stats::get();
+
+    let mut duration = Family::new(
+        "fritzlogger_poll_duration_seconds",
+        "gauge",
+        "Time it took to fetch the device list during the last successful poll.",
+    );
+    let mut last_success = Family::new(
+        "fritzlogger_last_successful_poll_timestamp_seconds",
+        "gauge",
+        "Unix time of the last successful poll.",
+    );
+    let mut failures = Family::new(
+        "fritzlogger_poll_failures_total",
+        "counter",
+        "Number of polls that failed.",
+    );
+
+    if let Some(d) = poll.duration {
+        duration.add("", d.as_secs_f64());
+    }
+    if let Some(t) = poll.last_success {
+        last_success.add("", t.as_secs_f64());
+    }
+    #[allow(clippy::cast_precision_loss)]
+    failures.add("", poll.failures as f64);
+
+    let mut out = String::new();
+    for family in &[duration, last_success, failures] {
+        family.render(&mut out);
+    }
+    out
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn serve(listener: &TcpListener, path: &str, devices: &Mutex<String>) {
+    for stream in listener.incoming() {
+        let result = stream
+            .chain_err(|| "Cannot accept connection")
+            .and_then(|stream| handle(stream, path, devices));
+        if let Err(e) = result {
+            print_errors(Error::with_chain(e, "Backend Prometheus: Scrape failed"));
+        }
+    }
+}
+
+fn handle(mut stream: TcpStream, path: &str, devices: &Mutex<String>) -> Result<()> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .chain_err(|| "Cannot set read timeout")?;
+
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).chain_err(|| "Cannot read request")?;
+        if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(p)) if p == path => {
+            let mut body = devices.lock().unwrap().clone();
+            body.push_str(&render_internal());
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                CONTENT_TYPE,
+                body.len(),
+                body
+            )
+        }
+        (Some("GET"), Some(_)) => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into()
+        }
+        _ => "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into(),
+    };
+
+    stream
+        .write_all(response.as_bytes())
+        .chain_err(|| "Cannot send response")
+}
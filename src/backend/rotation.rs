@@ -0,0 +1,67 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Replaces the file by a gzipped copy with an added `.gz` extension. An
+/// existing archive, e.g. of a day continued after a restart, gets another
+/// gzip member appended which decompresses to the concatenated content.
+pub fn compress(path: &Path) -> io::Result<()> {
+    let mut gz_path = path.as_os_str().to_owned();
+    gz_path.push(".gz");
+
+    let mut input = File::open(path)?;
+    let output = OpenOptions::new().create(true).append(true).open(gz_path)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+/// Deletes files rotated from `<name>.<extension>` which were last modified
+/// longer than `max_age` ago.
+pub fn remove_expired(
+    dir: &Path,
+    name: &str,
+    extension: &str,
+    max_age: Duration,
+) -> io::Result<()> {
+    let now = SystemTime::now();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !is_rotated(&entry.file_name().to_string_lossy(), name, extension) {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        let age = now.duration_since(modified).unwrap_or_default();
+        if age > max_age {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the file is named `<name>-<period>[.N].<extension>[.gz]` where the
+/// period consists of digits, dashes and `T` like the rotated ones.
+fn is_rotated(file_name: &str, name: &str, extension: &str) -> bool {
+    let rest = file_name
+        .strip_prefix(name)
+        .and_then(|rest| rest.strip_prefix('-'))
+        .map(|rest| rest.strip_suffix(".gz").unwrap_or(rest))
+        .and_then(|rest| rest.strip_suffix(extension))
+        .and_then(|rest| rest.strip_suffix('.'));
+    rest.into_iter().any(|rest| {
+        let mut parts = rest.splitn(2, '.');
+        let period = parts.next().unwrap_or_default();
+        let n = parts.next();
+        period.starts_with(|c: char| c.is_ascii_digit())
+            && period
+                .chars()
+                .all(|c| c.is_ascii_digit() || c == '-' || c == 'T')
+            && n.into_iter()
+                .all(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    })
+}
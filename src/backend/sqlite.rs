@@ -0,0 +1,275 @@
+use super::Backend;
+use crate::device::{Device, Recorded};
+use crate::errors::*;
+use crate::inventory::Event;
+use crate::settings;
+
+use config::Value;
+use error_chain::bail;
+use rusqlite::{params, Connection, Transaction, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+const JOURNAL_MODES: &[&str] = &["DELETE", "TRUNCATE", "PERSIST", "MEMORY", "WAL", "OFF"];
+
+// Every entry upgrades the schema by one version. Never change an entry
+// once released; append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE devices (
+        ain TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL,
+        manufacturer TEXT NOT NULL,
+        product TEXT NOT NULL,
+        firmware TEXT NOT NULL,
+        functions INTEGER NOT NULL,
+        first_seen INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+    CREATE TABLE temperature (
+        ain TEXT NOT NULL REFERENCES devices(ain),
+        timestamp INTEGER NOT NULL,
+        temperature INTEGER NOT NULL,
+        \"offset\" INTEGER NOT NULL,
+        PRIMARY KEY (ain, timestamp)
+    ) WITHOUT ROWID;
+    CREATE TABLE powermeter (
+        ain TEXT NOT NULL REFERENCES devices(ain),
+        timestamp INTEGER NOT NULL,
+        voltage INTEGER NOT NULL,
+        power INTEGER NOT NULL,
+        energy INTEGER NOT NULL,
+        PRIMARY KEY (ain, timestamp)
+    ) WITHOUT ROWID;
+",
+    "
+    CREATE TABLE device_events (
+        timestamp INTEGER NOT NULL,
+        ain TEXT NOT NULL,
+        name TEXT NOT NULL,
+        event TEXT NOT NULL,
+        old TEXT NOT NULL,
+        new TEXT NOT NULL
+    );
+    CREATE INDEX device_events_ain ON device_events (ain, timestamp);
+",
+    "
+    ALTER TABLE temperature ADD COLUMN sensor INTEGER;
+",
+    "
+    ALTER TABLE devices ADD COLUMN alias TEXT NOT NULL DEFAULT '';
+    ALTER TABLE devices ADD COLUMN room TEXT NOT NULL DEFAULT '';
+    ALTER TABLE devices ADD COLUMN tags TEXT NOT NULL DEFAULT '';
+",
+    // The reported temperature is left out when only the sensor is recorded.
+    "
+    CREATE TABLE temperature_new (
+        ain TEXT NOT NULL REFERENCES devices(ain),
+        timestamp INTEGER NOT NULL,
+        temperature INTEGER,
+        \"offset\" INTEGER NOT NULL,
+        sensor INTEGER,
+        PRIMARY KEY (ain, timestamp)
+    ) WITHOUT ROWID;
+    INSERT INTO temperature_new (ain, timestamp, temperature, \"offset\", sensor)
+        SELECT ain, timestamp, temperature, \"offset\", sensor FROM temperature;
+    DROP TABLE temperature;
+    ALTER TABLE temperature_new RENAME TO temperature;
+",
+];
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    path: String,
+    journal_mode: String,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Sqlite> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("path".into(), "fritzlogger.sqlite".into()),
+            ("journal_mode".into(), "WAL".into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+pub struct Sqlite {
+    conn: Connection,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Sqlite {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Sqlite"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let journal_mode = settings.journal_mode.to_uppercase();
+        if !JOURNAL_MODES.contains(&journal_mode.as_str()) {
+            bail!(
+                "Journal mode \"{}\" does not exist. These we do know: {:?}",
+                settings.journal_mode,
+                JOURNAL_MODES
+            );
+        }
+
+        let mut conn = Connection::open(&settings.path)
+            .chain_err(|| format!("Cannot open database {}", settings.path))?;
+        conn.query_row(
+            &format!("PRAGMA journal_mode = {}", journal_mode),
+            NO_PARAMS,
+            |row| row.get::<_, String>(0),
+        )
+        .chain_err(|| "Cannot set journal mode")?;
+        conn.execute("PRAGMA foreign_keys = ON", NO_PARAMS)
+            .chain_err(|| "Cannot enable foreign keys")?;
+        Self::migrate(&mut conn)?;
+
+        Ok(Self { conn, recorded })
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        let timestamp = i64::try_from(when.as_secs()).chain_err(|| "Timestamp out of range")?;
+        let tx = self
+            .conn
+            .transaction()
+            .chain_err(|| "Cannot start transaction")?;
+
+        for device in data {
+            Self::insert(&tx, timestamp, device, self.recorded)
+                .chain_err(|| format!("Cannot insert device {}", device.common.unique_id))?;
+        }
+
+        tx.commit().chain_err(|| "Cannot commit transaction")
+    }
+
+    fn event(&mut self, when: Duration, events: &[Event]) -> Result<()> {
+        let timestamp = i64::try_from(when.as_secs()).chain_err(|| "Timestamp out of range")?;
+        let tx = self
+            .conn
+            .transaction()
+            .chain_err(|| "Cannot start transaction")?;
+
+        for event in events {
+            tx.prepare_cached(
+                "INSERT INTO device_events (timestamp, ain, name, event, old, new)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    timestamp,
+                    event.ain,
+                    event.name,
+                    event.kind.name(),
+                    event.old,
+                    event.new,
+                ])
+            })
+            .chain_err(|| format!("Cannot insert event for device {}", event.ain))?;
+        }
+
+        tx.commit().chain_err(|| "Cannot commit transaction")
+    }
+}
+
+impl Sqlite {
+    fn migrate(conn: &mut Connection) -> Result<()> {
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", NO_PARAMS, |row| row.get(0))
+            .chain_err(|| "Cannot read schema version")?;
+        let version = usize::try_from(version).chain_err(|| "Invalid schema version")?;
+
+        if version > MIGRATIONS.len() {
+            bail!(
+                "Database schema version {} is newer than this fritzlogger supports ({})",
+                version,
+                MIGRATIONS.len()
+            );
+        }
+
+        let tx = conn
+            .transaction()
+            .chain_err(|| "Cannot start transaction")?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            tx.execute_batch(migration)
+                .chain_err(|| format!("Cannot migrate schema to version {}", i + 1))?;
+        }
+        tx.execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len()))
+            .chain_err(|| "Cannot store schema version")?;
+        tx.commit().chain_err(|| "Cannot commit schema migration")
+    }
+
+    fn insert(
+        tx: &Transaction,
+        timestamp: i64,
+        device: &Device,
+        recorded: Recorded,
+    ) -> rusqlite::Result<()> {
+        let common = &device.common;
+        tx.prepare_cached(
+            "INSERT INTO devices
+                (ain, name, manufacturer, product, firmware, functions, first_seen, last_seen,
+                 alias, room, tags)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, ?8, ?9, ?10)
+             ON CONFLICT (ain) DO UPDATE SET
+                name = excluded.name,
+                alias = excluded.alias,
+                room = excluded.room,
+                tags = excluded.tags,
+                manufacturer = excluded.manufacturer,
+                product = excluded.product,
+                firmware = excluded.firmware,
+                functions = excluded.functions,
+                last_seen = excluded.last_seen",
+        )?
+        .execute(params![
+            common.unique_id,
+            common.name,
+            common.manufacturer,
+            common.productname,
+            common.fwversion,
+            common.functions.bits(),
+            timestamp,
+            common.alias,
+            common.room,
+            common.tags.join(","),
+        ])?;
+
+        if let Some(temperature) = &device.temperature {
+            tx.prepare_cached(
+                "INSERT OR IGNORE INTO temperature (ain, timestamp, temperature, sensor, \"offset\")
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![
+                common.unique_id,
+                timestamp,
+                Some(temperature.temperature.raw()).filter(|_| recorded.reported()),
+                Some(temperature.sensor.raw()).filter(|_| recorded.sensor()),
+                temperature.offset.raw(),
+            ])?;
+        }
+
+        if let Some(powermeter) = &device.powermeter {
+            tx.prepare_cached(
+                "INSERT OR IGNORE INTO powermeter (ain, timestamp, voltage, power, energy)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?
+            .execute(params![
+                common.unique_id,
+                timestamp,
+                powermeter.voltage.raw(),
+                powermeter.power.raw(),
+                powermeter.energy.raw(),
+            ])?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Just enough of an HTTP server to test the backends that post somewhere.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+pub struct Request {
+    /// The request line and the headers with lowercased names.
+    pub head: Vec<String>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.head.iter().skip(1).find_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key == name => Some(value.trim()),
+                _ => None,
+            }
+        })
+    }
+}
+
+/// Answers one request per status and passes on what it received. Returns
+/// the url to post to.
+pub fn serve(statuses: &[u16]) -> (String, Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let statuses = statuses.to_vec();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for status in statuses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                head.push(match line.find(':') {
+                    Some(i) if !head.is_empty() => {
+                        format!("{}{}", line[..i].to_lowercase(), &line[i..])
+                    }
+                    _ => line.to_owned(),
+                });
+            }
+            let mut request = Request {
+                head,
+                body: String::new(),
+            };
+            let len = request
+                .header("content-length")
+                .map_or(0, |len| len.parse().unwrap());
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            request.body = String::from_utf8(body).unwrap();
+
+            write!(
+                stream,
+                "HTTP/1.1 {} Stand-in\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            if sender.send(request).is_err() {
+                return;
+            }
+        }
+    });
+    (url, receiver)
+}
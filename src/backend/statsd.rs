@@ -0,0 +1,134 @@
+use super::plaintext::{datagrams, sanitize};
+use super::Backend;
+use crate::device::{Device, Recorded};
+use crate::errors::*;
+use crate::settings;
+
+use config::Value;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    host: String,
+    port: u16,
+    prefix: String,
+    path: String,
+    tags: bool,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Statsd> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("host".into(), "localhost".into()),
+            ("port".into(), 8125.into()),
+            ("prefix".into(), "fritz".into()),
+            ("path".into(), "{name}".into()),
+            ("tags".into(), false.into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+pub struct Statsd {
+    settings: Settings,
+    socket: UdpSocket,
+    // last energy reading per AIN to derive the counter increments
+    energy: HashMap<String, f64>,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Statsd {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Statsd"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| {
+                socket
+                    .connect((settings.host.as_str(), settings.port))
+                    .map(|()| socket)
+            })
+            .chain_err(|| "Cannot open udp socket")?;
+        let ret = Self {
+            settings,
+            socket,
+            energy: HashMap::new(),
+            recorded,
+        };
+        Ok(ret)
+    }
+
+    fn log(&mut self, _: Duration, data: &[Device]) -> Result<()> {
+        let mut lines = String::new();
+        for device in data {
+            self.push_device(&mut lines, device);
+        }
+
+        for datagram in datagrams(&lines) {
+            self.socket
+                .send(datagram.as_bytes())
+                .chain_err(|| "Cannot send metrics to statsd")?;
+        }
+        Ok(())
+    }
+}
+
+impl Statsd {
+    fn push_device(&mut self, lines: &mut String, device: &Device) {
+        let common = &device.common;
+        let (name, tags) = if self.settings.tags {
+            let mut tags = format!(
+                "|#ain:{},name:{},product:{}",
+                sanitize(&common.unique_id),
+                sanitize(&common.name),
+                sanitize(&common.productname)
+            );
+            if !common.room.is_empty() {
+                write!(tags, ",room:{}", sanitize(&common.room))
+                    .expect("Writing to a String cannot fail.");
+            }
+            for tag in &common.tags {
+                write!(tags, ",{}", sanitize(tag)).expect("Writing to a String cannot fail.");
+            }
+            (self.settings.prefix.clone(), tags)
+        } else {
+            let path = self
+                .settings
+                .path
+                .replace("{ain}", &sanitize(&common.unique_id))
+                .replace("{name}", &sanitize(&common.name))
+                .replace("{alias}", &sanitize(common.label()));
+            (format!("{}.{}", self.settings.prefix, path), String::new())
+        };
+
+        for (metric, value) in device.readings(self.recorded) {
+            let (value, kind) = if metric == "energy" {
+                let last = self.energy.insert(common.unique_id.clone(), value);
+                match last {
+                    Some(last) if last <= value => (value - last, "c"),
+                    // first sight or the meter was reset
+                    _ => continue,
+                }
+            } else {
+                if value < 0.0 {
+                    // a signed gauge is a relative change in statsd
+                    writeln!(lines, "{}.{}:0|g{}", name, metric, tags)
+                        .expect("Writing to a String cannot fail.");
+                }
+                (value, "g")
+            };
+            writeln!(lines, "{}.{}:{}|{}{}", name, metric, value, kind, tags)
+                .expect("Writing to a String cannot fail.");
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use super::Backend;
+use crate::device::{Device, Recorded};
+use crate::errors::*;
+use crate::inventory::Event;
+use crate::{settings, stats};
+
+use chrono::DateTime;
+use config::Value;
+use error_chain::bail;
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, SystemTime};
+
+const FACILITIES: &[&str] = &[
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+// 32473 is the enterprise number reserved for documentation and examples
+const SD_ID: &str = "fritz@32473";
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_NOTICE: u8 = 5;
+const SEVERITY_INFO: u8 = 6;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    transport: String,
+    path: String,
+    host: String,
+    port: u16,
+    facility: String,
+    app_name: String,
+    hostname: String,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Syslog> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("transport".into(), "Unix".into()),
+            ("path".into(), "/dev/log".into()),
+            ("host".into(), "localhost".into()),
+            ("port".into(), 514.into()),
+            ("facility".into(), "daemon".into()),
+            ("app_name".into(), "fritzlogger".into()),
+            ("hostname".into(), "-".into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+enum Socket {
+    #[cfg(unix)]
+    Unix(UnixDatagram, PathBuf),
+    Udp(UdpSocket),
+}
+
+pub struct Syslog {
+    socket: Socket,
+    facility: u8,
+    app_name: String,
+    hostname: String,
+    failures: u64,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Syslog {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Syslog"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let facility = FACILITIES
+            .iter()
+            .position(|f| *f == settings.facility)
+            .chain_err(|| {
+                format!(
+                    "Facility \"{}\" does not exist. These we do know: {:?}",
+                    settings.facility, FACILITIES
+                )
+            })?;
+
+        let socket = match settings.transport.as_str() {
+            #[cfg(unix)]
+            "Unix" => {
+                let socket = UnixDatagram::unbound()
+                    .and_then(|socket| socket.connect(&settings.path).map(|()| socket))
+                    .chain_err(|| format!("Cannot connect to {}", settings.path))?;
+                Socket::Unix(socket, PathBuf::from(&settings.path))
+            }
+            "Udp" => {
+                let socket = UdpSocket::bind("0.0.0.0:0")
+                    .and_then(|socket| {
+                        socket
+                            .connect((settings.host.as_str(), settings.port))
+                            .map(|()| socket)
+                    })
+                    .chain_err(|| "Cannot open udp socket")?;
+                Socket::Udp(socket)
+            }
+            _ => bail!(
+                "Transport \"{}\" is not available. Use Unix or Udp",
+                settings.transport
+            ),
+        };
+
+        let ret = Self {
+            socket,
+            facility: u8::try_from(facility).expect("There are only 24 facilities."),
+            app_name: settings.app_name,
+            hostname: settings.hostname,
+            failures: stats::get().failures,
+            recorded,
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .chain_err(|| "Your system clock is skewed.")?;
+        ret.send(SEVERITY_NOTICE, now, "event", "", "fritzlogger started")?;
+        Ok(ret)
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        let failures = stats::get().failures;
+        if failures > self.failures {
+            let msg = format!(
+                "Polling the box failed {} times since the last sample",
+                failures - self.failures
+            );
+            self.failures = failures;
+            self.send(SEVERITY_WARNING, when, "event", "", &msg)?;
+        }
+
+        for device in data {
+            let common = &device.common;
+            let mut sd = format!(
+                "[{} ain=\"{}\" name=\"{}\" product=\"{}\"",
+                SD_ID,
+                escape(&common.unique_id),
+                escape(&common.name),
+                escape(&common.productname)
+            );
+            for (measurement, value) in device.readings(self.recorded) {
+                write!(sd, " {}=\"{}\"", measurement, value)
+                    .expect("Writing to a String cannot fail.");
+            }
+            sd.push(']');
+
+            self.send(SEVERITY_INFO, when, "reading", &sd, &common.name)?;
+        }
+        Ok(())
+    }
+
+    fn event(&mut self, when: Duration, events: &[Event]) -> Result<()> {
+        for event in events {
+            let sd = format!(
+                "[{} ain=\"{}\" name=\"{}\" event=\"{}\" old=\"{}\" new=\"{}\"]",
+                SD_ID,
+                escape(&event.ain),
+                escape(&event.name),
+                event.kind.name(),
+                escape(&event.old),
+                escape(&event.new)
+            );
+            let msg = format!("{} {}", event.name, event.kind.name());
+            self.send(SEVERITY_NOTICE, when, "device", &sd, &msg)?;
+        }
+        Ok(())
+    }
+}
+
+impl Syslog {
+    fn send(
+        &self,
+        severity: u8,
+        when: Duration,
+        msgid: &str,
+        structured_data: &str,
+        msg: &str,
+    ) -> Result<()> {
+        let secs = i64::try_from(when.as_secs()).chain_err(|| "Timestamp out of range")?;
+        let time = DateTime::from_timestamp(secs, 0).chain_err(|| "Timestamp out of range")?;
+        let message = format!(
+            "<{}>1 {} {} {} {} {} {} {}",
+            self.facility * 8 + severity,
+            time.format("%Y-%m-%dT%H:%M:%SZ"),
+            self.hostname,
+            self.app_name,
+            process::id(),
+            msgid,
+            if structured_data.is_empty() {
+                "-"
+            } else {
+                structured_data
+            },
+            msg
+        );
+
+        match &self.socket {
+            #[cfg(unix)]
+            // A restarted syslog daemon listens on a new socket.
+            Socket::Unix(socket, path) => socket.send(message.as_bytes()).or_else(|_| {
+                socket.connect(path)?;
+                socket.send(message.as_bytes())
+            }),
+            Socket::Udp(socket) => socket.send(message.as_bytes()),
+        }
+        .chain_err(|| "Cannot send message to syslog")?;
+        Ok(())
+    }
+}
+
+/// Escapes a structured data parameter value.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_param_values() {
+        assert_eq!(escape(r#"a "b" [c] \d"#), r#"a \"b\" [c\] \\d"#);
+        assert_eq!(escape("plain"), "plain");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reconnects_to_restarted_daemon() {
+        let path = std::env::temp_dir().join(format!("fritzlogger-syslog-{}", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        let syslog = Syslog::new(Settings {
+            transport: "Unix".to_owned(),
+            path: path.to_string_lossy().into_owned(),
+            host: String::new(),
+            port: 514,
+            facility: "daemon".to_owned(),
+            app_name: "fritzlogger".to_owned(),
+            hostname: "-".to_owned(),
+            temperature: "Both".to_owned(),
+        })
+        .unwrap();
+        let mut buf = [0; 1024];
+        daemon.recv(&mut buf).unwrap();
+
+        drop(daemon);
+        std::fs::remove_file(&path).unwrap();
+        let daemon = UnixDatagram::bind(&path).unwrap();
+        syslog
+            .send(SEVERITY_INFO, Duration::from_secs(1), "test", "", "again")
+            .unwrap();
+        let len = daemon.recv(&mut buf).unwrap();
+        assert!(buf[..len].ends_with(b" test - again"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
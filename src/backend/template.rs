@@ -0,0 +1,22 @@
+/// Replaces every known `{placeholder}` in a single pass so that values are
+/// never expanded again. Unknown ones are kept as they are.
+pub fn render<F: Fn(&str) -> Option<String>>(template: &str, value: F) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let replaced = rest
+            .find('}')
+            .and_then(|end| value(&rest[1..end]).map(|value| (end, value)));
+        if let Some((end, value)) = replaced {
+            rendered.push_str(&value);
+            rest = &rest[end + 1..];
+        } else {
+            rendered.push('{');
+            rest = &rest[1..];
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
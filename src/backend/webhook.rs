@@ -0,0 +1,287 @@
+use super::template::render;
+use super::Backend;
+use crate::device::{Device, Output, Recorded, READINGS};
+use crate::errors::*;
+use crate::settings;
+
+use config::Value;
+use error_chain::bail;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_POLL_TEMPLATE: &str = r#"{"timestamp":{timestamp},"devices":{devices}}"#;
+const DEFAULT_DEVICE_TEMPLATE: &str = r#"{"timestamp":{timestamp},"device":{device}}"#;
+
+#[derive(Deserialize, Serialize)]
+pub struct Settings {
+    url: String,
+    per: String,
+    template: String,
+    content_type: String,
+    headers: Vec<String>,
+    auth: String,
+    username: String,
+    password: String,
+    token: String,
+    retries: u32,
+    retry_delay: u64,
+    timeout: u64,
+    temperature: String,
+}
+
+impl<'de> settings::Settings<'de, Webhook> for Settings {
+    fn defaults() -> Vec<(String, Value)> {
+        vec![
+            ("url".into(), "http://localhost:8080/fritz".into()),
+            ("per".into(), "Poll".into()),
+            ("template".into(), "".into()),
+            ("content_type".into(), "application/json".into()),
+            ("headers".into(), Vec::<String>::new().into()),
+            ("auth".into(), "None".into()),
+            ("username".into(), "".into()),
+            ("password".into(), "".into()),
+            ("token".into(), "".into()),
+            ("retries".into(), 3.into()),
+            ("retry_delay".into(), 1.into()),
+            ("timeout".into(), 10.into()),
+            ("temperature".into(), "Both".into()),
+        ]
+    }
+}
+
+pub struct Webhook {
+    settings: Settings,
+    client: Client,
+    per_device: bool,
+    template: String,
+    recorded: Recorded,
+}
+
+impl<'de> Backend<'de> for Webhook {
+    type Settings = Settings;
+
+    fn name() -> &'static str {
+        "Webhook"
+    }
+
+    fn new(settings: Self::Settings) -> Result<Self> {
+        let recorded = Recorded::parse(&settings.temperature)?;
+        let per_device = match settings.per.as_str() {
+            "Poll" => false,
+            "Device" => true,
+            _ => bail!(
+                "Per \"{}\" does not exist. Use Poll or Device",
+                settings.per
+            ),
+        };
+        match settings.auth.as_str() {
+            "None" | "Basic" | "Bearer" => (),
+            _ => bail!(
+                "Auth \"{}\" does not exist. Use None, Basic or Bearer",
+                settings.auth
+            ),
+        }
+
+        let mut headers = HeaderMap::new();
+        let content_type =
+            HeaderValue::from_str(&settings.content_type).chain_err(|| "Invalid content type")?;
+        headers.insert(CONTENT_TYPE, content_type);
+        for header in &settings.headers {
+            let mut parts = header.splitn(2, ':');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name.trim(), value.trim()),
+                _ => bail!("Header \"{}\" must look like \"Name: value\"", header),
+            };
+            headers.insert(
+                HeaderName::from_bytes(name.as_bytes())
+                    .chain_err(|| format!("Invalid header name {}", name))?,
+                HeaderValue::from_str(value)
+                    .chain_err(|| format!("Invalid value for header {}", name))?,
+            );
+        }
+
+        let client = Client::builder()
+            .default_headers(headers)
+            .timeout(Duration::from_secs(settings.timeout))
+            .build()
+            .chain_err(|| "Cannot create http client")?;
+
+        let template = if !settings.template.is_empty() {
+            settings.template.clone()
+        } else if per_device {
+            DEFAULT_DEVICE_TEMPLATE.into()
+        } else {
+            DEFAULT_POLL_TEMPLATE.into()
+        };
+
+        let ret = Self {
+            settings,
+            client,
+            per_device,
+            template,
+            recorded,
+        };
+        Ok(ret)
+    }
+
+    fn log(&mut self, when: Duration, data: &[Device]) -> Result<()> {
+        let timestamp = when.as_secs().to_string();
+        if self.per_device {
+            // one failed device must not keep the others from being posted
+            let mut failed = Vec::new();
+            let mut cause = None;
+            for device in data {
+                let body = self.render_device(&timestamp, device)?;
+                if let Err(e) = self.post(&body) {
+                    failed.push(device.common.unique_id.as_str());
+                    cause.get_or_insert(e);
+                }
+            }
+            match cause {
+                Some(e) => Err(Error::with_chain(
+                    e,
+                    format!("Webhook failed for devices {}", failed.join(", ")),
+                )),
+                None => Ok(()),
+            }
+        } else {
+            let devices = serde_json::to_string(&Output::all(data, self.recorded))
+                .chain_err(|| "Cannot serialize devices")?;
+            let body = render(&self.template, |key| match key {
+                "timestamp" => Some(timestamp.clone()),
+                "devices" => Some(devices.clone()),
+                _ => None,
+            });
+            self.post(&body)
+        }
+    }
+}
+
+impl Webhook {
+    /// Placeholders are replaced by JSON values so that the default content
+    /// type stays valid no matter what the device is called.
+    fn render_device(&self, timestamp: &str, device: &Device) -> Result<String> {
+        let common = &device.common;
+        let output = serde_json::to_string(&Output::new(device, self.recorded))
+            .chain_err(|| "Cannot serialize device")?;
+        let readings = device.readings(self.recorded);
+        let body = render(&self.template, |key| {
+            let value = match key {
+                "timestamp" => timestamp.to_owned(),
+                "ain" => json!(common.unique_id).to_string(),
+                "name" => json!(common.name).to_string(),
+                "alias" => json!(common.label()).to_string(),
+                "room" => json!(common.room).to_string(),
+                "tags" => json!(common.tags).to_string(),
+                "product" => json!(common.productname).to_string(),
+                "device" => output.clone(),
+                _ if READINGS.contains(&key) => readings
+                    .iter()
+                    .find(|(reading, _)| *reading == key)
+                    .map_or_else(|| "null".to_owned(), |(_, value)| value.to_string()),
+                _ => return None,
+            };
+            Some(value)
+        });
+        Ok(body)
+    }
+
+    fn post(&self, body: &str) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            let response = self
+                .authorize(self.client.post(&self.settings.url))
+                .body(body.to_owned())
+                .send()
+                .chain_err(|| "Sending webhook failed")?;
+            let status = response.status();
+
+            if status.is_success() {
+                return Ok(());
+            }
+            if !status.is_server_error() || attempt >= self.settings.retries {
+                bail!("Webhook answered with status {}", status);
+            }
+
+            attempt += 1;
+            thread::sleep(Duration::from_secs(self.settings.retry_delay) * attempt);
+        }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.settings.auth.as_str() {
+            "Basic" => request.basic_auth(&self.settings.username, Some(&self.settings.password)),
+            "Bearer" => request.bearer_auth(&self.settings.token),
+            _ => request,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::stand_in;
+    use crate::device::tests::device;
+
+    fn webhook(url: String, per: &str, template: &str) -> Webhook {
+        let settings = Settings {
+            url,
+            per: per.to_owned(),
+            template: template.to_owned(),
+            content_type: "application/json".to_owned(),
+            headers: vec!["X-Test: yes".to_owned()],
+            auth: "Bearer".to_owned(),
+            username: String::new(),
+            password: String::new(),
+            token: "secret".to_owned(),
+            retries: 0,
+            retry_delay: 0,
+            timeout: 5,
+            temperature: "Both".to_owned(),
+        };
+        Webhook::new(settings).unwrap()
+    }
+
+    #[test]
+    fn does_not_expand_values_again() {
+        let mut desk = device("1");
+        desk.common.name = "{name} {power} {ain}".to_owned();
+        let webhook = webhook(
+            String::new(),
+            "Device",
+            r#"{"n":{name},"p":{power},"x":{x}}"#,
+        );
+        let body = webhook.render_device("2", &desk).unwrap();
+        assert_eq!(body, r#"{"n":"{name} {power} {ain}","p":12.5,"x":{x}}"#);
+    }
+
+    #[test]
+    fn posts_poll() {
+        let (url, requests) = stand_in::serve(&[200]);
+        let mut webhook = webhook(url, "Poll", "{timestamp}: {devices}");
+        webhook.log(Duration::from_secs(2), &[device("1")]).unwrap();
+        let request = requests.recv().unwrap();
+        assert_eq!(request.head[0], "POST / HTTP/1.1");
+        assert_eq!(request.header("authorization"), Some("Bearer secret"));
+        assert_eq!(request.header("x-test"), Some("yes"));
+        assert!(request
+            .body
+            .starts_with(r#"2: [{"common":{"unique_id":"1","#));
+    }
+
+    #[test]
+    fn posts_remaining_devices_after_a_failure() {
+        let (url, requests) = stand_in::serve(&[200, 404, 200]);
+        let mut webhook = webhook(url, "Device", "{ain}");
+        let data = [device("1"), device("2"), device("3")];
+        let error = webhook.log(Duration::from_secs(2), &data).unwrap_err();
+        assert_eq!(error.to_string(), "Webhook failed for devices 2");
+        let bodies: Vec<_> = requests.iter().map(|r| r.body).collect();
+        assert_eq!(bodies, [r#""1""#, r#""2""#, r#""3""#]);
+    }
+}
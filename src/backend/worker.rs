@@ -0,0 +1,644 @@
+use super::Backend;
+use crate::device::Device;
+use crate::errors::*;
+use crate::inventory::Event;
+use crate::print_errors;
+
+use error_chain::bail;
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// What to do with a new sample when the queue of a backend is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    DropOldest,
+    /// Waits up to the block timeout for a free slot. Polling the box and
+    /// all other backends stall meanwhile.
+    Block,
+    Spool,
+}
+
+impl Overflow {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "DropOldest" => Ok(Overflow::DropOldest),
+            "Block" => Ok(Overflow::Block),
+            "Spool" => Ok(Overflow::Spool),
+            _ => bail!(
+                "Overflow policy \"{}\" does not exist. Use DropOldest, Block or Spool",
+                name
+            ),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Options {
+    pub queue_size: usize,
+    /// How long `Overflow::Block` waits for a free slot.
+    pub block_timeout: Duration,
+    /// How long a single write may take before the backend is abandoned
+    /// and a fresh one takes over.
+    pub write_timeout: Duration,
+    pub overflow: Overflow,
+    pub spool_dir: PathBuf,
+}
+
+enum Payload {
+    Devices(Arc<Vec<Device>>),
+    Events(Arc<Vec<Event>>),
+}
+
+struct Job {
+    when: Duration,
+    payload: Payload,
+}
+
+#[derive(Serialize)]
+struct SpoolEntry<'a> {
+    when: Duration,
+    devices: &'a [Device],
+    events: &'a [Event],
+}
+
+#[derive(Deserialize)]
+struct SpoolEntryOwned {
+    when: Duration,
+    devices: Vec<Device>,
+    events: Vec<Event>,
+}
+
+enum Next {
+    Job(Job),
+    Replay(PathBuf),
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    // As long as this is set new samples go to the spool file instead of
+    // the queue. Otherwise they would overtake the spooled ones.
+    spooled: bool,
+}
+
+struct Shared {
+    name: &'static str,
+    options: Options,
+    state: Mutex<State>,
+    job_ready: Condvar,
+    space_ready: Condvar,
+}
+
+type Factory<B> = Box<dyn Fn() -> Result<B> + Send>;
+
+/// Runs the backend on a thread of its own so that a write which hangs can
+/// be given up on. The hung thread is left behind and a backend created
+/// from scratch takes the next write.
+struct Runner<B> {
+    name: &'static str,
+    factory: Factory<B>,
+    timeout: Duration,
+    channels: Option<(Sender<Job>, Receiver<Result<()>>)>,
+}
+
+impl<'de, B: Backend<'de> + Send + 'static> Runner<B> {
+    fn new(name: &'static str, backend: B, factory: Factory<B>, timeout: Duration) -> Result<Self> {
+        let mut ret = Self {
+            name,
+            factory,
+            timeout,
+            channels: None,
+        };
+        ret.start(backend)?;
+        Ok(ret)
+    }
+
+    fn start(&mut self, mut backend: B) -> Result<()> {
+        let (job_tx, job_rx) = mpsc::channel::<Job>();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::Builder::new()
+            .name(format!("writer-{}", self.name))
+            .spawn(move || {
+                for job in job_rx {
+                    let result = match &job.payload {
+                        Payload::Devices(devices) => backend.log(job.when, devices),
+                        Payload::Events(events) => backend.event(job.when, events),
+                    };
+                    // nobody is waiting anymore once the write timed out
+                    if done_tx.send(result).is_err() {
+                        break;
+                    }
+                }
+            })
+            .chain_err(|| "Cannot start writer thread")?;
+        self.channels = Some((job_tx, done_rx));
+        Ok(())
+    }
+
+    fn call(&mut self, job: Job) -> Result<()> {
+        if self.channels.is_none() {
+            let backend = (self.factory)().chain_err(|| "Cannot restart backend")?;
+            self.start(backend)?;
+        }
+        if let Some((jobs, done)) = &self.channels {
+            if jobs.send(job).is_ok() {
+                match done.recv_timeout(self.timeout) {
+                    Ok(result) => return result,
+                    Err(RecvTimeoutError::Timeout) => {
+                        self.channels = None;
+                        bail!(
+                            "Write did not finish within {:?}. Restarting the backend",
+                            self.timeout
+                        );
+                    }
+                    Err(RecvTimeoutError::Disconnected) => {}
+                }
+            }
+        }
+        self.channels = None;
+        bail!("Backend crashed. Restarting it");
+    }
+}
+
+/// Owns a backend and feeds it from a bounded queue on a dedicated thread.
+///
+/// Samples are written strictly in the order they were pushed. What happens
+/// when the backend cannot keep up is decided by the configured `Overflow`.
+pub struct Worker {
+    shared: Arc<Shared>,
+}
+
+impl Worker {
+    /// The factory creates a replacement whenever a write of the backend
+    /// hangs or panics.
+    pub fn spawn<'de, B, F>(backend: B, factory: F, options: Options) -> Result<Self>
+    where
+        B: Backend<'de> + Send + 'static,
+        F: Fn() -> Result<B> + Send + 'static,
+    {
+        let name = <B as Backend>::name();
+        if options.overflow == Overflow::Spool {
+            fs::create_dir_all(&options.spool_dir)
+                .chain_err(|| format!("Cannot create spool directory for backend {}", name))?;
+        }
+
+        let shared = Arc::new(Shared {
+            name,
+            state: Mutex::new(State {
+                jobs: VecDeque::with_capacity(options.queue_size),
+                spooled: false,
+            }),
+            options,
+            job_ready: Condvar::new(),
+            space_ready: Condvar::new(),
+        });
+        // pick up samples left over from the last run
+        shared.state.lock().unwrap().spooled =
+            shared.spool_path().exists() || shared.replay_path().exists();
+
+        let runner = Runner::new(
+            name,
+            backend,
+            Box::new(factory),
+            shared.options.write_timeout,
+        )?;
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name(format!("backend-{}", name))
+            .spawn(move || thread_shared.run(runner))
+            .chain_err(|| format!("Cannot start worker for backend {}", name))?;
+
+        Ok(Self { shared })
+    }
+
+    pub fn push(&self, when: Duration, devices: &Arc<Vec<Device>>) {
+        self.enqueue(Job {
+            when,
+            payload: Payload::Devices(devices.clone()),
+        });
+    }
+
+    pub fn push_events(&self, when: Duration, events: &Arc<Vec<Event>>) {
+        self.enqueue(Job {
+            when,
+            payload: Payload::Events(events.clone()),
+        });
+    }
+
+    fn enqueue(&self, job: Job) {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+
+        if state.spooled {
+            shared.spool_or_drop(&job);
+            return;
+        }
+
+        if state.jobs.len() >= shared.options.queue_size {
+            match shared.options.overflow {
+                Overflow::DropOldest => {
+                    state.jobs.pop_front();
+                    shared.report("Queue is full. Dropped the oldest sample");
+                }
+                Overflow::Block => {
+                    if let Some(waited) = shared.wait_for_space(state) {
+                        state = waited;
+                    } else {
+                        shared.report(&format!(
+                            "Queue stayed full for {:?}. Dropped the newest sample",
+                            shared.options.block_timeout
+                        ));
+                        return;
+                    }
+                }
+                Overflow::Spool => {
+                    if shared.spool_or_drop(&job) {
+                        state.spooled = true;
+                        shared.report("Queue is full. Spooling samples to disk");
+                    }
+                    return;
+                }
+            }
+        }
+
+        state.jobs.push_back(job);
+        shared.job_ready.notify_one();
+    }
+}
+
+impl Shared {
+    fn run<'de, B: Backend<'de> + Send + 'static>(&self, mut runner: Runner<B>) {
+        loop {
+            let next = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if let Some(job) = state.jobs.pop_front() {
+                        break Next::Job(job);
+                    }
+                    if state.spooled {
+                        match self.take_spool() {
+                            Ok((path, more)) => {
+                                state.spooled = more;
+                                break Next::Replay(path);
+                            }
+                            Err(e) => {
+                                state.spooled = false;
+                                self.report_error(e);
+                            }
+                        }
+                    }
+                    state = self.job_ready.wait(state).unwrap();
+                }
+            };
+            self.space_ready.notify_one();
+
+            match next {
+                Next::Job(job) => self.write(&mut runner, job),
+                Next::Replay(path) => {
+                    if let Err(e) = self.replay(&mut runner, &path) {
+                        // The file is still in place. Leave it for the next
+                        // start instead of replaying it over and over.
+                        self.state.lock().unwrap().spooled = false;
+                        self.report_error(e);
+                    }
+                }
+            }
+        }
+    }
+
+    fn write<'de, B: Backend<'de> + Send + 'static>(&self, runner: &mut Runner<B>, job: Job) {
+        if let Err(e) = runner.call(job) {
+            self.report_error(e);
+        }
+    }
+
+    fn wait_for_space<'a>(
+        &self,
+        mut state: MutexGuard<'a, State>,
+    ) -> Option<MutexGuard<'a, State>> {
+        let deadline = Instant::now() + self.options.block_timeout;
+        while state.jobs.len() >= self.options.queue_size {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            state = self
+                .space_ready
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        Some(state)
+    }
+
+    fn spool_path(&self) -> PathBuf {
+        self.options.spool_dir.join(format!("{}.spool", self.name))
+    }
+
+    fn replay_path(&self) -> PathBuf {
+        self.options.spool_dir.join(format!("{}.replay", self.name))
+    }
+
+    fn corrupt_path(&self) -> PathBuf {
+        let secs = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        self.options
+            .spool_dir
+            .join(format!("{}.corrupt-{}", self.name, secs))
+    }
+
+    /// Returns whether the sample made it into the spool file.
+    fn spool_or_drop(&self, job: &Job) -> bool {
+        let entry = match &job.payload {
+            Payload::Devices(devices) => SpoolEntry {
+                when: job.when,
+                devices,
+                events: &[],
+            },
+            Payload::Events(events) => SpoolEntry {
+                when: job.when,
+                devices: &[],
+                events,
+            },
+        };
+        match self.spool(&entry) {
+            Ok(()) => true,
+            Err(e) => {
+                self.report_error(Error::with_chain(
+                    e,
+                    "Dropped sample that could not be spooled",
+                ));
+                false
+            }
+        }
+    }
+
+    fn spool(&self, entry: &SpoolEntry) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.spool_path())
+            .chain_err(|| "Cannot open spool file")?;
+        let mut line =
+            serde_json::to_vec(entry).chain_err(|| "Cannot serialize sample for spooling")?;
+        line.push(b'\n');
+        file.write_all(&line)
+            .chain_err(|| "Cannot write to spool file")
+    }
+
+    /// Returns the file to replay and whether there is still a spool file
+    /// left that needs to be replayed afterwards.
+    fn take_spool(&self) -> Result<(PathBuf, bool)> {
+        let replay = self.replay_path();
+        // an unfinished replay from the last run is older than the spool
+        if replay.exists() {
+            return Ok((replay, self.spool_path().exists()));
+        }
+        fs::rename(self.spool_path(), &replay).chain_err(|| "Cannot rotate spool file")?;
+        Ok((replay, false))
+    }
+
+    /// A file is replayed at most once. Should it be unreadable it is moved
+    /// aside so that it can be inspected instead of being retried forever.
+    fn replay<'de, B: Backend<'de> + Send + 'static>(
+        &self,
+        runner: &mut Runner<B>,
+        path: &Path,
+    ) -> Result<()> {
+        match self.replay_lines(runner, path) {
+            Ok(()) => fs::remove_file(path).chain_err(|| "Cannot remove replayed spool file"),
+            Err(e) => {
+                self.report_error(e);
+                let aside = self.corrupt_path();
+                fs::rename(path, &aside).chain_err(|| {
+                    format!("Cannot move unreadable spool file to {}", aside.display())
+                })?;
+                self.report(&format!(
+                    "Moved unreadable spool file to {}",
+                    aside.display()
+                ));
+                Ok(())
+            }
+        }
+    }
+
+    // Nothing is synced to disk while spooling. Hence a crash can leave a
+    // torn line behind which must not keep the rest from being replayed.
+    fn replay_lines<'de, B: Backend<'de> + Send + 'static>(
+        &self,
+        runner: &mut Runner<B>,
+        path: &Path,
+    ) -> Result<()> {
+        let file = File::open(path).chain_err(|| "Cannot open spool file for replay")?;
+        let mut skipped = 0;
+        for line in BufReader::new(file).split(b'\n') {
+            let line = line.chain_err(|| "Cannot read spool file")?;
+            if let Ok(entry) = serde_json::from_slice::<SpoolEntryOwned>(&line) {
+                let payload = if entry.events.is_empty() {
+                    Payload::Devices(Arc::new(entry.devices))
+                } else {
+                    Payload::Events(Arc::new(entry.events))
+                };
+                self.write(
+                    runner,
+                    Job {
+                        when: entry.when,
+                        payload,
+                    },
+                );
+            } else {
+                skipped += 1;
+            }
+        }
+        if skipped > 0 {
+            self.report(&format!(
+                "Skipped {} corrupted lines while replaying the spool file",
+                skipped
+            ));
+        }
+        Ok(())
+    }
+
+    fn report(&self, msg: &str) {
+        print_errors(Error::from(format!("Backend {}: {}", self.name, msg)));
+    }
+
+    fn report_error(&self, e: Error) {
+        print_errors(Error::with_chain(
+            e,
+            format!("Backend {} failed", self.name),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::tests::device;
+    use crate::inventory::EventKind;
+    use crate::settings;
+
+    use std::process;
+
+    #[derive(Clone, Default)]
+    struct Recorder {
+        logged: Arc<Mutex<Vec<u64>>>,
+        events: Arc<Mutex<Vec<u64>>>,
+        // hangs while logging a sample taken at this second
+        hang_at: Option<u64>,
+    }
+
+    impl<'de> Backend<'de> for Recorder {
+        type Settings = settings::No<Self>;
+
+        fn name() -> &'static str {
+            "Recorder"
+        }
+
+        fn new(_: Self::Settings) -> Result<Self> {
+            Ok(Self::default())
+        }
+
+        fn log(&mut self, when: Duration, _: &[Device]) -> Result<()> {
+            if self.hang_at == Some(when.as_secs()) {
+                thread::sleep(Duration::from_secs(5));
+            }
+            self.logged.lock().unwrap().push(when.as_secs());
+            Ok(())
+        }
+
+        fn event(&mut self, when: Duration, _: &[Event]) -> Result<()> {
+            self.events.lock().unwrap().push(when.as_secs());
+            Ok(())
+        }
+    }
+
+    fn runner(backend: &Recorder) -> Runner<Recorder> {
+        let fresh = Recorder {
+            hang_at: None,
+            ..backend.clone()
+        };
+        Runner::new(
+            "Recorder",
+            backend.clone(),
+            Box::new(move || Ok(fresh.clone())),
+            Duration::from_millis(100),
+        )
+        .unwrap()
+    }
+
+    fn shared(test: &str) -> Shared {
+        let spool_dir =
+            std::env::temp_dir().join(format!("fritzlogger-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&spool_dir);
+        fs::create_dir_all(&spool_dir).unwrap();
+        Shared {
+            name: "Recorder",
+            options: Options {
+                queue_size: 1,
+                block_timeout: Duration::from_secs(1),
+                write_timeout: Duration::from_millis(100),
+                overflow: Overflow::Spool,
+                spool_dir,
+            },
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                spooled: false,
+            }),
+            job_ready: Condvar::new(),
+            space_ready: Condvar::new(),
+        }
+    }
+
+    fn spool_devices(shared: &Shared, secs: u64) {
+        let job = Job {
+            when: Duration::from_secs(secs),
+            payload: Payload::Devices(Arc::new(vec![device("1")])),
+        };
+        assert!(shared.spool_or_drop(&job));
+    }
+
+    #[test]
+    fn replays_in_order_and_skips_torn_lines() {
+        let shared = shared("replay");
+        spool_devices(&shared, 1);
+        let event = Event {
+            ain: "1".to_owned(),
+            name: "Desk".to_owned(),
+            kind: EventKind::Added,
+            old: String::new(),
+            new: String::new(),
+        };
+        let job = Job {
+            when: Duration::from_secs(2),
+            payload: Payload::Events(Arc::new(vec![event])),
+        };
+        assert!(shared.spool_or_drop(&job));
+        // a crash in the middle of a line, which the next sample continues
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(shared.spool_path())
+            .unwrap();
+        file.write_all(br#"{"when":{"se"#).unwrap();
+        spool_devices(&shared, 3);
+        spool_devices(&shared, 4);
+
+        let (path, more) = shared.take_spool().unwrap();
+        assert!(!more);
+        let backend = Recorder::default();
+        shared.replay(&mut runner(&backend), &path).unwrap();
+        assert_eq!(*backend.logged.lock().unwrap(), [1, 4]);
+        assert_eq!(*backend.events.lock().unwrap(), [2]);
+        assert!(!path.exists());
+        assert!(!shared.spool_path().exists());
+
+        fs::remove_dir_all(&shared.options.spool_dir).unwrap();
+    }
+
+    #[test]
+    fn replays_unfinished_replay_first() {
+        let shared = shared("unfinished");
+        spool_devices(&shared, 1);
+        fs::rename(shared.spool_path(), shared.replay_path()).unwrap();
+        spool_devices(&shared, 2);
+
+        let backend = Recorder::default();
+        let mut runner = runner(&backend);
+        let (path, more) = shared.take_spool().unwrap();
+        assert!(more);
+        shared.replay(&mut runner, &path).unwrap();
+        let (path, more) = shared.take_spool().unwrap();
+        assert!(!more);
+        shared.replay(&mut runner, &path).unwrap();
+        assert_eq!(*backend.logged.lock().unwrap(), [1, 2]);
+        assert!(shared.take_spool().is_err());
+
+        fs::remove_dir_all(&shared.options.spool_dir).unwrap();
+    }
+
+    #[test]
+    fn restarts_hung_backend() {
+        let backend = Recorder {
+            hang_at: Some(1),
+            ..Recorder::default()
+        };
+        let mut runner = runner(&backend);
+        let job = |secs| Job {
+            when: Duration::from_secs(secs),
+            payload: Payload::Devices(Arc::new(vec![device("1")])),
+        };
+
+        assert!(runner.call(job(1)).is_err());
+        runner.call(job(2)).unwrap();
+        assert_eq!(*backend.logged.lock().unwrap(), [2]);
+    }
+}
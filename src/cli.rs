@@ -22,5 +22,15 @@ pub fn get_args() -> ArgMatches<'static> {
             SubCommand::with_name("defconfig")
                 .about("Output a complete config containing all default values"),
         )
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("Output the rows of an archive file as CSV")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .help("Path to the archive file.")
+                        .required(true),
+                ),
+        )
         .get_matches()
 }
@@ -4,19 +4,203 @@ use crate::{errors::*, fetch_body};
 use bitflags::bitflags;
 use error_chain::bail;
 use futures::Future;
+use once_cell::sync::OnceCell;
 use reqwest::r#async::{Client, RequestBuilder};
 use roxmltree::{Document, Node};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use std::collections::HashMap;
+use std::fmt;
 use std::sync::Arc;
 
 const LOCATION_AHA: &str = "/webservices/homeautoswitch.lua";
 const ROOT_NAME: &str = "devicelist";
+/// Every measurement `Device::readings` may return.
+pub const READINGS: &[&str] = &[
+    "present",
+    "temperature",
+    "sensor_temperature",
+    "offset",
+    "power",
+    "voltage",
+    "energy",
+];
 
-#[derive(Debug)]
+static RAW_UNITS: OnceCell<bool> = OnceCell::new();
+static ALIASES: OnceCell<HashMap<String, DeviceConfig>> = OnceCell::new();
+
+/// Selects whether readings are reported as the raw integers of the AHA
+/// interface instead of SI scaled decimals. Only the first call counts.
+pub fn set_raw_units(raw: bool) {
+    let _ = RAW_UNITS.set(raw);
+}
+
+pub fn raw_units() -> bool {
+    RAW_UNITS.get().copied().unwrap_or(false)
+}
+
+/// Which of the two temperatures a backend records.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Recorded {
+    Both,
+    /// As reported by the box, i.e. with the offset applied.
+    Reported,
+    /// Not affected by changes to the offset.
+    Sensor,
+}
+
+impl Recorded {
+    pub fn parse(name: &str) -> Result<Self> {
+        match name {
+            "Both" => Ok(Recorded::Both),
+            "Reported" => Ok(Recorded::Reported),
+            "Sensor" => Ok(Recorded::Sensor),
+            _ => bail!(
+                "Temperature \"{}\" does not exist. Use Both, Reported or Sensor",
+                name
+            ),
+        }
+    }
+
+    pub fn reported(self) -> bool {
+        self != Recorded::Sensor
+    }
+
+    pub fn sensor(self) -> bool {
+        self != Recorded::Reported
+    }
+}
+
+/// What the configuration adds to a device of the box.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DeviceConfig {
+    pub alias: String,
+    pub room: String,
+    pub tags: Vec<String>,
+}
+
+/// Spaces in AINs are ignored as the box is inconsistent about them. Case is
+/// ignored as the configuration lowercases keys.
+fn normalize_ain(ain: &str) -> String {
+    ain.chars()
+        .filter(|c| !c.is_whitespace())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Sets the aliases, rooms and tags by AIN that are applied to every device
+/// list fetched from now on. Only the first call counts.
+pub fn set_aliases(devices: HashMap<String, DeviceConfig>) -> Result<()> {
+    let mut aliases = HashMap::new();
+    for (ain, config) in devices {
+        let normalized = normalize_ain(&ain);
+        if normalized.is_empty() {
+            bail!("Device \"{}\" has no AIN", ain);
+        }
+        if aliases.insert(normalized, config).is_some() {
+            bail!("Device {} is configured more than once", ain);
+        }
+    }
+    let _ = ALIASES.set(aliases);
+    Ok(())
+}
+
+/// A reading that serializes to the configured units.
+#[derive(Clone, Copy)]
+pub struct Reading {
+    raw: i64,
+    value: f64,
+}
+
+impl Serialize for Reading {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        if raw_units() {
+            serializer.serialize_i64(self.raw)
+        } else {
+            serializer.serialize_f64(self.value)
+        }
+    }
+}
+
+// Declares a newtype around the integer the box reports. It always
+// serializes to that integer so that spooled samples do not depend on
+// the configuration. Use `output` for anything presented to the user.
+macro_rules! unit {
+    ($(#[$attr:meta])* $name:ident($raw:ty), $scale:expr) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+        #[serde(transparent)]
+        pub struct $name(pub $raw);
+
+        impl $name {
+            /// The integer as reported by the box.
+            pub fn raw(self) -> $raw {
+                self.0
+            }
+
+            pub fn value(self) -> f64 {
+                f64::from(self.0) / $scale
+            }
+
+            /// Either the raw or the scaled value depending on the configuration.
+            pub fn reading(self) -> f64 {
+                if raw_units() {
+                    f64::from(self.0)
+                } else {
+                    self.value()
+                }
+            }
+
+            pub fn output(self) -> Reading {
+                Reading {
+                    raw: i64::from(self.0),
+                    value: self.value(),
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                if raw_units() {
+                    write!(f, "{}", self.0)
+                } else {
+                    write!(f, "{}", self.value())
+                }
+            }
+        }
+    };
+}
+
+unit!(
+    /// Tenths of a degree Celsius.
+    Celsius(i16),
+    10.0
+);
+unit!(
+    /// Milliwatts.
+    Watts(u32),
+    1000.0
+);
+unit!(
+    /// Millivolts.
+    Volts(u32),
+    1000.0
+);
+unit!(
+    /// Watt hours.
+    WattHours(u32),
+    1.0
+);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Device {
     pub common: Common,
     pub temperature: Option<Temperature>,
     pub powermeter: Option<Powermeter>,
+    /// Measurements held back by `change_only` because they stayed within
+    /// their deadband.
+    pub unchanged: Vec<String>,
 }
 
 bitflags! {
@@ -33,7 +217,19 @@ bitflags! {
     }
 }
 
-#[derive(Debug)]
+impl Serialize for Functions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.bits())
+    }
+}
+
+impl<'de> Deserialize<'de> for Functions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        u16::deserialize(deserializer).map(Self::from_bits_truncate)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Common {
     pub unique_id: String,
     pub internal_id: u32,
@@ -43,19 +239,30 @@ pub struct Common {
     pub productname: String,
     pub name: String,
     pub present: bool,
+    // The following come from the configuration instead of the box.
+    #[serde(default)]
+    pub alias: String,
+    #[serde(default)]
+    pub room: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Temperature {
-    pub temperature: i16,
-    pub offset: i16,
+    /// As reported by the box, i.e. with the offset already applied.
+    pub temperature: Celsius,
+    /// The uncorrected value of the sensor.
+    pub sensor: Celsius,
+    /// Configured by the user in the box.
+    pub offset: Celsius,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Powermeter {
-    pub voltage: u32,
-    pub power: u32,
-    pub energy: u32,
+    pub voltage: Volts,
+    pub power: Watts,
+    pub energy: WattHours,
 }
 
 impl Common {
@@ -77,19 +284,44 @@ impl Common {
                 "1" => true,
                 _ => bail!("Present must be 0 or 1"),
             },
+            alias: String::new(),
+            room: String::new(),
+            tags: Vec::new(),
         };
         Ok(common)
     }
+
+    /// The alias if one is configured. Otherwise the name from the box.
+    pub fn label(&self) -> &str {
+        if self.alias.is_empty() {
+            &self.name
+        } else {
+            &self.alias
+        }
+    }
+
+    fn apply_alias(&mut self) {
+        let ain = normalize_ain(&self.unique_id);
+        let config = ALIASES.get().and_then(|aliases| aliases.get(&ain));
+        if let Some(config) = config {
+            self.alias.clone_from(&config.alias);
+            self.room.clone_from(&config.room);
+            self.tags.clone_from(&config.tags);
+        }
+    }
 }
 
 impl Temperature {
     fn parse(node: &Node) -> Result<Self> {
         let temp = get_child(node, "temperature")?;
+        let temperature = i16::from_str_radix(get_child_text(&temp, "celsius")?, 10)
+            .chain_err(|| "Cannot convert temperature to number")?;
+        let offset = i16::from_str_radix(get_child_text(&temp, "offset")?, 10)
+            .chain_err(|| "Cannot convert offset to number")?;
         let ret = Self {
-            temperature: i16::from_str_radix(get_child_text(&temp, "celsius")?, 10)
-                .chain_err(|| "Cannot convert temperature to number")?,
-            offset: i16::from_str_radix(get_child_text(&temp, "offset")?, 10)
-                .chain_err(|| "Cannot convert offset to number")?,
+            temperature: Celsius(temperature),
+            sensor: Celsius(temperature.saturating_sub(offset)),
+            offset: Celsius(offset),
         };
         Ok(ret)
     }
@@ -100,10 +332,13 @@ impl Powermeter {
         let power = get_child(node, "powermeter")?;
         let ret = Self {
             voltage: u32::from_str_radix(get_child_text(&power, "voltage")?, 10)
+                .map(Volts)
                 .chain_err(|| "Cannot convert voltage to number")?,
             power: u32::from_str_radix(get_child_text(&power, "power")?, 10)
+                .map(Watts)
                 .chain_err(|| "Cannot convert power to number")?,
             energy: u32::from_str_radix(get_child_text(&power, "energy")?, 10)
+                .map(WattHours)
                 .chain_err(|| "Cannot convert energy to number")?,
         };
         Ok(ret)
@@ -112,7 +347,8 @@ impl Powermeter {
 
 impl Device {
     fn parse(node: &Node) -> Result<Self> {
-        let common = Common::parse(node)?;
+        let mut common = Common::parse(node)?;
+        common.apply_alias();
         let mut temperature = None;
         let mut powermeter = None;
 
@@ -128,9 +364,89 @@ impl Device {
             common,
             temperature,
             powermeter,
+            unchanged: Vec::new(),
         };
         Ok(device)
     }
+
+    /// The values of all readings this device offers, keyed by measurement.
+    /// They are SI scaled unless raw units are configured. Unchanged ones
+    /// and temperatures that are not recorded are left out.
+    pub fn readings(&self, recorded: Recorded) -> Vec<(&'static str, f64)> {
+        let mut readings = vec![("present", f64::from(u8::from(self.common.present)))];
+        if let Some(t) = &self.temperature {
+            if recorded.reported() {
+                readings.push(("temperature", t.temperature.reading()));
+            }
+            if recorded.sensor() {
+                readings.push(("sensor_temperature", t.sensor.reading()));
+            }
+            readings.push(("offset", t.offset.reading()));
+        }
+        if let Some(p) = &self.powermeter {
+            readings.push(("power", p.power.reading()));
+            readings.push(("voltage", p.voltage.reading()));
+            readings.push(("energy", p.energy.reading()));
+        }
+        readings.retain(|(measurement, _)| self.changed(measurement));
+        readings
+    }
+
+    /// Whether the measurement is to be passed on. Always true unless the
+    /// backend only gets changes.
+    pub fn changed(&self, measurement: &str) -> bool {
+        !self.unchanged.iter().any(|m| m == measurement)
+    }
+}
+
+/// A device as presented to the user, i.e. with its readings in the
+/// configured units. Otherwise the same shape as `Device` itself.
+#[derive(Serialize)]
+pub struct Output<'a> {
+    common: &'a Common,
+    temperature: Option<TemperatureOutput>,
+    powermeter: Option<PowermeterOutput>,
+}
+
+#[derive(Serialize)]
+struct TemperatureOutput {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<Reading>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sensor: Option<Reading>,
+    offset: Reading,
+}
+
+#[derive(Serialize)]
+struct PowermeterOutput {
+    voltage: Reading,
+    power: Reading,
+    energy: Reading,
+}
+
+impl<'a> Output<'a> {
+    pub fn new(device: &'a Device, recorded: Recorded) -> Self {
+        Self {
+            common: &device.common,
+            temperature: device.temperature.as_ref().map(|t| TemperatureOutput {
+                temperature: Some(t.temperature.output()).filter(|_| recorded.reported()),
+                sensor: Some(t.sensor.output()).filter(|_| recorded.sensor()),
+                offset: t.offset.output(),
+            }),
+            powermeter: device.powermeter.as_ref().map(|p| PowermeterOutput {
+                voltage: p.voltage.output(),
+                power: p.power.output(),
+                energy: p.energy.output(),
+            }),
+        }
+    }
+
+    pub fn all(devices: &'a [Device], recorded: Recorded) -> Vec<Self> {
+        devices
+            .iter()
+            .map(|device| Self::new(device, recorded))
+            .collect()
+    }
 }
 
 fn parse_devices(body: &str) -> Result<Arc<Vec<Device>>> {
@@ -163,3 +479,44 @@ pub fn devicelistinfos(
     let request = build_request(client, base_url, sid);
     fetch_body(request).and_then(|body| parse_devices(&body))
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A switch with a thermometer as the box reports it.
+    pub fn device(ain: &str) -> Device {
+        Device {
+            common: Common {
+                unique_id: ain.to_owned(),
+                internal_id: 16,
+                functions: Functions::TEMPERATURE_SENSOR | Functions::ENERGY_METER,
+                fwversion: "04.16".to_owned(),
+                manufacturer: "AVM".to_owned(),
+                productname: "FRITZ!DECT 200".to_owned(),
+                name: "Desk".to_owned(),
+                present: true,
+                alias: String::new(),
+                room: String::new(),
+                tags: Vec::new(),
+            },
+            temperature: Some(Temperature {
+                temperature: Celsius(215),
+                sensor: Celsius(220),
+                offset: Celsius(-5),
+            }),
+            powermeter: Some(Powermeter {
+                voltage: Volts(230_000),
+                power: Watts(12_500),
+                energy: WattHours(4711),
+            }),
+            unchanged: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn ignores_spaces_and_case_of_ains() {
+        assert_eq!(normalize_ain(" 08761 0000434"), "087610000434");
+        assert_eq!(normalize_ain("Z1A 2B"), "z1a2b");
+    }
+}
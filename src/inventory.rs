@@ -0,0 +1,257 @@
+use crate::device::Device;
+use crate::errors::*;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Added,
+    Removed,
+    Renamed,
+    FirmwareUpdated,
+    Absent,
+    Present,
+}
+
+impl EventKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            EventKind::Added => "added",
+            EventKind::Removed => "removed",
+            EventKind::Renamed => "renamed",
+            EventKind::FirmwareUpdated => "firmware_updated",
+            EventKind::Absent => "absent",
+            EventKind::Present => "present",
+        }
+    }
+}
+
+/// A change of the metadata of a device as opposed to its readings.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Event {
+    pub ain: String,
+    /// The name of the device after the change.
+    pub name: String,
+    pub kind: EventKind,
+    /// Previous and current value of what changed. Empty where this does
+    /// not apply.
+    pub old: String,
+    pub new: String,
+}
+
+impl Event {
+    fn new(ain: &str, name: &str, kind: EventKind, old: &str, new: &str) -> Self {
+        Self {
+            ain: ain.to_owned(),
+            name: name.to_owned(),
+            kind,
+            old: old.to_owned(),
+            new: new.to_owned(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    name: String,
+    productname: String,
+    fwversion: String,
+    present: bool,
+    first_seen: u64,
+}
+
+/// Every device ever seen by AIN. It is persisted to a state file so that
+/// restarts do not report all devices as added again.
+pub struct Inventory {
+    path: PathBuf,
+    devices: BTreeMap<String, Entry>,
+}
+
+impl Inventory {
+    pub fn load(path: &Path) -> Result<Self> {
+        let devices = match fs::read(path) {
+            Ok(content) => serde_json::from_slice(&content)
+                .chain_err(|| format!("Corrupted inventory {}", path.display()))?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(Error::with_chain(
+                    e,
+                    format!("Cannot read inventory {}", path.display()),
+                ))
+            }
+        };
+        let ret = Self {
+            path: path.to_owned(),
+            devices,
+        };
+        Ok(ret)
+    }
+
+    /// Compares the devices with the inventory and returns what changed.
+    pub fn update(&mut self, when: Duration, devices: &[Device]) -> Vec<Event> {
+        let secs = when.as_secs();
+        let mut events = Vec::new();
+
+        for device in devices {
+            let common = &device.common;
+            let ain = &common.unique_id;
+            if let Some(entry) = self.devices.get_mut(ain) {
+                if entry.name != common.name {
+                    events.push(Event::new(
+                        ain,
+                        &common.name,
+                        EventKind::Renamed,
+                        &entry.name,
+                        &common.name,
+                    ));
+                    entry.name.clone_from(&common.name);
+                }
+                if entry.fwversion != common.fwversion {
+                    events.push(Event::new(
+                        ain,
+                        &common.name,
+                        EventKind::FirmwareUpdated,
+                        &entry.fwversion,
+                        &common.fwversion,
+                    ));
+                    entry.fwversion.clone_from(&common.fwversion);
+                }
+                if entry.present != common.present {
+                    let kind = if common.present {
+                        EventKind::Present
+                    } else {
+                        EventKind::Absent
+                    };
+                    events.push(Event::new(ain, &common.name, kind, "", ""));
+                    entry.present = common.present;
+                }
+                entry.productname.clone_from(&common.productname);
+            } else {
+                events.push(Event::new(
+                    ain,
+                    &common.name,
+                    EventKind::Added,
+                    "",
+                    &common.productname,
+                ));
+                self.devices.insert(
+                    ain.clone(),
+                    Entry {
+                        name: common.name.clone(),
+                        productname: common.productname.clone(),
+                        fwversion: common.fwversion.clone(),
+                        present: common.present,
+                        first_seen: secs,
+                    },
+                );
+            }
+        }
+
+        let removed: Vec<_> = self
+            .devices
+            .keys()
+            .filter(|ain| devices.iter().all(|d| &d.common.unique_id != *ain))
+            .cloned()
+            .collect();
+        for ain in removed {
+            let entry = self.devices.remove(&ain).expect("AIN was just found.");
+            events.push(Event::new(
+                &ain,
+                &entry.name,
+                EventKind::Removed,
+                &entry.productname,
+                "",
+            ));
+        }
+
+        events
+    }
+
+    /// Written to a temporary file first so that a crash never leaves
+    /// a truncated inventory behind.
+    pub fn save(&self) -> Result<()> {
+        let content =
+            serde_json::to_vec_pretty(&self.devices).chain_err(|| "Cannot serialize inventory")?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, content)
+            .chain_err(|| format!("Cannot write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &self.path)
+            .chain_err(|| format!("Cannot move {} into place", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::tests::device;
+
+    fn kinds(events: &[Event]) -> Vec<EventKind> {
+        events.iter().map(|e| e.kind).collect()
+    }
+
+    fn inventory() -> Inventory {
+        Inventory {
+            path: PathBuf::new(),
+            devices: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn reports_added_devices_once() {
+        let mut inventory = inventory();
+        let devices = [device("1"), device("2")];
+        let events = inventory.update(Duration::from_secs(10), &devices);
+        assert_eq!(kinds(&events), [EventKind::Added, EventKind::Added]);
+        assert_eq!(events[0].new, "FRITZ!DECT 200");
+        assert!(inventory
+            .update(Duration::from_secs(20), &devices)
+            .is_empty());
+    }
+
+    #[test]
+    fn reports_changes() {
+        let mut inventory = inventory();
+        let mut desk = device("1");
+        inventory.update(Duration::from_secs(10), &[desk.clone()]);
+
+        desk.common.name = "Lamp".to_owned();
+        desk.common.fwversion = "04.17".to_owned();
+        desk.common.present = false;
+        let events = inventory.update(Duration::from_secs(20), &[desk.clone()]);
+        assert_eq!(
+            kinds(&events),
+            [
+                EventKind::Renamed,
+                EventKind::FirmwareUpdated,
+                EventKind::Absent
+            ]
+        );
+        assert_eq!(
+            (events[0].old.as_str(), events[0].new.as_str()),
+            ("Desk", "Lamp")
+        );
+        assert_eq!(events[1].old, "04.16");
+        assert!(events.iter().all(|e| e.name == "Lamp"));
+
+        desk.common.present = true;
+        let events = inventory.update(Duration::from_secs(30), &[desk]);
+        assert_eq!(kinds(&events), [EventKind::Present]);
+    }
+
+    #[test]
+    fn reports_removed_devices() {
+        let mut inventory = inventory();
+        inventory.update(Duration::from_secs(10), &[device("1"), device("2")]);
+        let events = inventory.update(Duration::from_secs(20), &[device("2")]);
+        assert_eq!(kinds(&events), [EventKind::Removed]);
+        assert_eq!(events[0].ain, "1");
+        assert_eq!(events[0].old, "FRITZ!DECT 200");
+    }
+}
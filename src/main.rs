@@ -19,7 +19,9 @@ mod auth;
 mod backend;
 mod cli;
 mod device;
+mod inventory;
 mod settings;
+mod stats;
 mod xml;
 
 mod errors {
@@ -61,19 +63,28 @@ fn app(
     )
     .and_then(move |sid| {
         Interval::new(Instant::now(), poll_interval)
-            .for_each(move |_| {
+            .for_each(move |started| {
                 device::devicelistinfos(&client, &settings.url, &sid)
-                    .map(move |list| {
-                        let t = SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .expect("Your system clock is skewed.");
-                        Dispatcher::dispatch(t, &list)
-                    })
                     .or_else(|e| {
                         let err = Error::with_chain(e, "Failed getting device infos");
                         print_errors(&err);
-                        Ok(())
+                        stats::record_failure();
+                        Err(())
+                    })
+                    .and_then(move |list| {
+                        let t = SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .expect("Your system clock is skewed.");
+                        stats::record_success(started.elapsed(), t);
+                        // a backend with overflow = "Block" may wait here
+                        future::poll_fn(move || {
+                            tokio_threadpool::blocking(|| Dispatcher::dispatch(t, &list))
+                        })
+                        .map_err(|e| {
+                            print_errors(Error::with_chain(e, "Cannot dispatch devices"));
+                        })
                     })
+                    .then(|_| Ok(()))
             })
             .map_err(|e| Error::with_chain(e, "Interval failed"))
     })
@@ -85,7 +96,9 @@ fn command_run(args: &ArgMatches<'static>) -> Result<()> {
         .chain_err(|| "Config file must be specified")?;
     settings::load(cfg_path)?;
     let settings: settings::Base = settings::get_base()?;
-    Dispatcher::init(&settings.backends)?;
+    device::set_raw_units(settings.raw_units);
+    device::set_aliases(settings::get_devices()?)?;
+    Dispatcher::init(&settings)?;
     let client = Client::new();
     let poll_interval = Duration::from_secs(settings.interval);
     let app = app(client, settings, poll_interval).map_err(print_errors);
@@ -98,10 +111,18 @@ fn command_defconfig(_: &ArgMatches<'static>) -> Result<()> {
     Ok(())
 }
 
+fn command_dump(args: &ArgMatches<'static>) -> Result<()> {
+    let path = args
+        .value_of("file")
+        .chain_err(|| "Archive file must be specified")?;
+    backend::archive::dump(path)
+}
+
 fn run() -> Result<()> {
     match cli::get_args().subcommand() {
         ("run", Some(sub)) => command_run(&sub),
         ("defconfig", Some(sub)) => command_defconfig(&sub),
+        ("dump", Some(sub)) => command_dump(&sub),
         _ => Err("Inconsistent command line. This is a bug.".into()),
     }
 }
@@ -1,10 +1,12 @@
 use crate::backend::Backend;
+use crate::device::DeviceConfig;
 use crate::errors::*;
 
-use config::{Config as CConfig, File, Value};
+use config::{Config as CConfig, ConfigError, File, Value};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
+use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::sync::Mutex;
 
@@ -49,6 +51,16 @@ pub struct Base {
     pub password: String,
     pub interval: u64,
     pub backends: Vec<String>,
+    pub queue_size: usize,
+    pub block_timeout: u64,
+    pub write_timeout: u64,
+    pub overflow: String,
+    pub spool_dir: String,
+    pub raw_units: bool,
+    pub change_only: Vec<String>,
+    pub deadbands: Vec<String>,
+    pub heartbeat: u64,
+    pub inventory_file: String,
 }
 
 impl Named for Base {
@@ -65,6 +77,19 @@ impl<'de> Settings<'de, Base> for Base {
             ("password".into(), "".into()),
             ("interval".into(), 60.into()),
             ("backends".into(), vec!["Console", "Csv"].into()),
+            ("queue_size".into(), 16.into()),
+            ("block_timeout".into(), 30.into()),
+            ("write_timeout".into(), 30.into()),
+            ("overflow".into(), "DropOldest".into()),
+            ("spool_dir".into(), "spool".into()),
+            ("raw_units".into(), false.into()),
+            ("change_only".into(), Vec::<String>::new().into()),
+            (
+                "deadbands".into(),
+                vec!["temperature:0.2", "power:1", "voltage:2"].into(),
+            ),
+            ("heartbeat".into(), 900.into()),
+            ("inventory_file".into(), "inventory.json".into()),
         ]
     }
 }
@@ -123,6 +148,15 @@ pub fn get_base() -> Result<Base> {
     get_with_config::<Base, Base>(&CONFIG.lock().unwrap())
 }
 
+/// The optional `[devices]` table of aliases, rooms and tags by AIN.
+pub fn get_devices() -> Result<HashMap<String, DeviceConfig>> {
+    match CONFIG.lock().unwrap().config.get("devices") {
+        Ok(devices) => Ok(devices),
+        Err(ConfigError::NotFound(_)) => Ok(HashMap::new()),
+        Err(e) => Err(Error::with_chain(e, "Cannot get settings for devices")),
+    }
+}
+
 pub fn get_for_backend<'de, T: Backend<'de>>() -> Result<T::Settings> {
     get_with_config(&CONFIG.lock().unwrap())
 }
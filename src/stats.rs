@@ -0,0 +1,28 @@
+use once_cell::sync::Lazy;
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+static POLL: Lazy<Mutex<Poll>> = Lazy::new(|| Mutex::new(Poll::default()));
+
+/// Health of the daemon itself as opposed to the devices it watches.
+#[derive(Clone, Default)]
+pub struct Poll {
+    pub duration: Option<Duration>,
+    pub last_success: Option<Duration>,
+    pub failures: u64,
+}
+
+pub fn record_success(duration: Duration, when: Duration) {
+    let mut poll = POLL.lock().unwrap();
+    poll.duration = Some(duration);
+    poll.last_success = Some(when);
+}
+
+pub fn record_failure() {
+    POLL.lock().unwrap().failures += 1;
+}
+
+pub fn get() -> Poll {
+    POLL.lock().unwrap().clone()
+}